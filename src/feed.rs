@@ -0,0 +1,134 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A feed that is polled on its own, with its own watermark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Feed {
+    /// Shown in DMs and used in the watermark's file name.
+    pub name: String,
+    pub url: String,
+    /// How long to wait between polls.
+    pub check: Duration,
+    /// How long to wait after a poll failed.
+    pub failure: Duration,
+}
+
+/// An entry of the `RSS_FEEDS` file, intervals are in seconds.
+#[derive(Deserialize)]
+struct FeedConfig {
+    name: String,
+    url: String,
+    check: Option<u64>,
+    failure: Option<u64>,
+}
+
+impl Feed {
+    /// Where the publication date of the newest item seen is kept.
+    pub fn watermark(&self, store_folder: &Path) -> PathBuf {
+        store_folder.join(format!("last-{}.txt", self.name))
+    }
+
+    /// Where the ids of the items already handled are kept.
+    pub fn seen(&self, store_folder: &Path) -> PathBuf {
+        store_folder.join(format!("seen-{}.json", self.name))
+    }
+
+    /// Where the ETag and Last-Modified of the last response are kept.
+    pub fn validators(&self, store_folder: &Path) -> PathBuf {
+        store_folder.join(format!("validators-{}.json", self.name))
+    }
+}
+
+/// Parses the `RSS_FEEDS` file, feeds without intervals get the defaults.
+pub fn parse_feeds(data: &[u8], check: Duration, failure: Duration) -> Result<Vec<Feed>> {
+    let configs: Vec<FeedConfig> =
+        serde_json::from_slice(data).map_err(|e| anyhow!("invalid feeds file: {e}"))?;
+    if configs.is_empty() {
+        bail!("the feeds file lists no feeds");
+    }
+    let mut names = HashSet::new();
+    let mut feeds = Vec::with_capacity(configs.len());
+    for c in configs {
+        check_name(&c.name)?;
+        if !names.insert(c.name.clone()) {
+            bail!("there are two feeds named `{}`", c.name);
+        }
+        url::Url::parse(&c.url).map_err(|e| anyhow!("feed `{}`: {e}", c.name))?;
+        feeds.push(Feed {
+            name: c.name,
+            url: c.url,
+            check: c.check.map_or(check, Duration::from_secs),
+            failure: c.failure.map_or(failure, Duration::from_secs),
+        });
+    }
+    Ok(feeds)
+}
+
+/// The feed of `RSS_URL`, named after its host.
+pub fn single_feed(url: &str, check: Duration, failure: Duration) -> Result<Feed> {
+    let parsed = url::Url::parse(url).map_err(|e| anyhow!("RSS_URL: {e}"))?;
+    let name = parsed.host_str().unwrap_or("default").to_string();
+    check_name(&name)?;
+    Ok(Feed {
+        name,
+        url: url.to_string(),
+        check,
+        failure,
+    })
+}
+
+/// Fails if one of the names a subscription is limited to isn't a
+/// configured feed, it would never match anything.
+pub fn check_known(names: &[String], feeds: &[Feed]) -> Result<()> {
+    let known = |name: &String| feeds.iter().any(|f| f.name.eq_ignore_ascii_case(name));
+    if let Some(unknown) = names.iter().find(|n| !known(n)) {
+        let available: Vec<&str> = feeds.iter().map(|f| f.name.as_str()).collect();
+        bail!(
+            "there is no feed `{unknown}`, available are {}",
+            available.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(valid) {
+        bail!("feed names may only contain letters, digits, -, _ and ., got `{name}`");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_feeds() {
+        let data = br#"[
+            {"name": "nyaa-anime", "url": "https://nyaa.si/?page=rss&c=1_2"},
+            {"name": "nyaa-music", "url": "https://nyaa.si/?page=rss&c=2_0", "check": 600, "failure": 900}
+        ]"#;
+        let (check, failure) = (Duration::from_secs(60), Duration::from_secs(180));
+        let feeds = parse_feeds(data, check, failure).unwrap();
+        assert_eq!(feeds[0].check, check);
+        assert_eq!(feeds[1].failure, Duration::from_secs(900));
+        assert_eq!(
+            feeds[1].watermark(Path::new("/store")),
+            PathBuf::from("/store/last-nyaa-music.txt")
+        );
+
+        let same_name =
+            br#"[{"name": "a", "url": "https://a.b"}, {"name": "a", "url": "https://c.d"}]"#;
+        assert!(parse_feeds(same_name, check, failure).is_err());
+        let bad_name = br#"[{"name": "../a", "url": "https://a.b"}]"#;
+        assert!(parse_feeds(bad_name, check, failure).is_err());
+        assert!(parse_feeds(b"[]", check, failure).is_err());
+
+        let feed = single_feed("https://nyaa.si/?page=rss", check, failure).unwrap();
+        assert_eq!(feed.name, "nyaa.si");
+    }
+}
@@ -0,0 +1,320 @@
+use crate::history::Record;
+use crate::profile::Profile;
+use crate::query::{Pattern, Query};
+use crate::store::{Delivered, Entry, Flags};
+use anyhow::{bail, Result};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Start of every versioned `user.bin`, followed by the format version as a
+/// little endian `u16` and the bincode encoded entries, profiles and next id.
+const MAGIC: &[u8; 4] = b"MKMA";
+
+/// Version of the entry layout written by this build.
+///
+/// 0: list of substrings per user, stored without header
+/// 1: entries with a query, flags and delivered episodes
+/// 2: entries with a persistent id
+/// 3: entries and user profiles
+/// 4: entries, user profiles and notification history
+/// 5: entries that can be limited to feeds
+/// 6: the next subscription id, so ids of removed entries aren't reused
+/// 7: entries, user profiles and the next id, the history has its own file
+pub const VERSION: u16 = 7;
+
+/// The last version with the history in the payload.
+const HISTORY_UNTIL: u16 = 6;
+
+type Migration = fn(&[u8]) -> Result<Vec<u8>>;
+
+/// `MIGRATIONS[v]` turns the payload of version `v` into version `v + 1`.
+/// Changing [`Entry`] means bumping [`VERSION`], keeping the old layout
+/// around as its own type and adding a step here.
+const MIGRATIONS: [Migration; VERSION as usize] = [
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7,
+];
+
+#[derive(Default)]
+pub struct Contents {
+    pub entries: Vec<Entry>,
+    pub profiles: BTreeMap<u64, Profile>,
+    /// Oldest first. Only in the payload of versions 4 to 6, the file
+    /// backend keeps it in [`crate::history::FILE_NAME`] since.
+    pub history: Vec<Record>,
+    /// Higher than every id ever given out.
+    pub next_id: u64,
+}
+
+/// What [`decode`] read.
+pub struct Decoded {
+    pub contents: Contents,
+    /// The version found in the file, `None` if it had no header.
+    pub version: Option<u16>,
+}
+
+impl Decoded {
+    /// Whether the file should be rewritten in the current format.
+    pub fn is_outdated(&self) -> bool {
+        self.version != Some(VERSION)
+    }
+}
+
+// same encoding as bincode::serialize, but strict so one layout can't be
+// half-read as another
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+pub fn encode(
+    entries: &[Entry],
+    profiles: &BTreeMap<u64, Profile>,
+    next_id: u64,
+) -> Result<Vec<u8>> {
+    let mut data = MAGIC.to_vec();
+    data.extend(VERSION.to_le_bytes());
+    data.extend(options().serialize(&(entries, profiles, next_id))?);
+    Ok(data)
+}
+
+pub fn decode(data: &[u8]) -> Result<Decoded> {
+    let (version, payload) = match data.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 2 => {
+            let version = u16::from_le_bytes([rest[0], rest[1]]);
+            (Some(version), &rest[2..])
+        }
+        Some(_) => bail!("user store header is truncated"),
+        None => (None, data),
+    };
+    let from = match version {
+        Some(v) if v > VERSION => {
+            bail!("user store has format version {v}, this build only reads up to {VERSION}")
+        }
+        Some(v) => v,
+        // files from before the header, the newer layout is tried first
+        None if options().deserialize::<Vec<EntryV1>>(payload).is_ok() => 1,
+        None => 0,
+    };
+    Ok(Decoded {
+        contents: upgrade(from, payload.to_vec())?,
+        version,
+    })
+}
+
+/// Runs the migrations from version `from` on a bincode encoded payload.
+/// The history of older payloads is taken out before it is migrated away.
+pub fn upgrade(from: u16, mut payload: Vec<u8>) -> Result<Contents> {
+    let mut history = Vec::new();
+    for (v, migrate) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        log::warn!("migrating user store from format version {v} to {}", v + 1);
+        if v == HISTORY_UNTIL as usize {
+            let (_, _, h, _): (Vec<Entry>, BTreeMap<u64, Profile>, Vec<Record>, u64) =
+                options().deserialize(&payload)?;
+            history = h;
+        }
+        payload = migrate(&payload)?;
+    }
+    let (entries, profiles, next_id) = options().deserialize(&payload)?;
+    Ok(Contents {
+        entries,
+        profiles,
+        history,
+        next_id,
+    })
+}
+
+/// Version 0, a list of substrings that all had to match.
+#[derive(Serialize, Deserialize)]
+struct EntryV0 {
+    uid: u64,
+    patterns: Vec<String>,
+}
+
+/// Versions 1 to 4, [`Flags`] without feeds.
+#[derive(Serialize, Deserialize, Default)]
+struct FlagsV1 {
+    exact: bool,
+    renotify: bool,
+    prefer: Vec<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+/// Version 1, [`Entry`] without an id.
+#[derive(Serialize, Deserialize)]
+struct EntryV1 {
+    uid: u64,
+    query: Query,
+    flags: FlagsV1,
+    delivered: BTreeMap<String, Delivered>,
+}
+
+/// Versions 2 to 4, [`Entry`] with [`FlagsV1`].
+#[derive(Serialize, Deserialize)]
+struct EntryV2 {
+    uid: u64,
+    id: u64,
+    query: Query,
+    flags: FlagsV1,
+    delivered: BTreeMap<String, Delivered>,
+}
+
+fn v0_to_v1(data: &[u8]) -> Result<Vec<u8>> {
+    let old: Vec<EntryV0> = options().deserialize(data)?;
+    let entries: Vec<EntryV1> = old
+        .into_iter()
+        .map(|e| {
+            let terms = e
+                .patterns
+                .into_iter()
+                .map(|p| Query::Term(Pattern::Substring(p)));
+            EntryV1 {
+                uid: e.uid,
+                query: Query::And(terms.collect()),
+                flags: FlagsV1::default(),
+                delivered: BTreeMap::new(),
+            }
+        })
+        .collect();
+    Ok(options().serialize(&entries)?)
+}
+
+fn v1_to_v2(data: &[u8]) -> Result<Vec<u8>> {
+    let old: Vec<EntryV1> = options().deserialize(data)?;
+    let entries: Vec<EntryV2> = (1..)
+        .zip(old)
+        .map(|(id, e)| EntryV2 {
+            uid: e.uid,
+            id,
+            query: e.query,
+            flags: e.flags,
+            delivered: e.delivered,
+        })
+        .collect();
+    Ok(options().serialize(&entries)?)
+}
+
+fn v2_to_v3(data: &[u8]) -> Result<Vec<u8>> {
+    let entries: Vec<EntryV2> = options().deserialize(data)?;
+    Ok(options().serialize(&(entries, BTreeMap::<u64, Profile>::new()))?)
+}
+
+fn v3_to_v4(data: &[u8]) -> Result<Vec<u8>> {
+    let (entries, profiles): (Vec<EntryV2>, BTreeMap<u64, Profile>) =
+        options().deserialize(data)?;
+    Ok(options().serialize(&(entries, profiles, Vec::<Record>::new()))?)
+}
+
+fn v4_to_v5(data: &[u8]) -> Result<Vec<u8>> {
+    let (old, profiles, history): (Vec<EntryV2>, BTreeMap<u64, Profile>, Vec<Record>) =
+        options().deserialize(data)?;
+    let entries: Vec<Entry> = old
+        .into_iter()
+        .map(|e| {
+            let flags = Flags {
+                exact: e.flags.exact,
+                renotify: e.flags.renotify,
+                prefer: e.flags.prefer,
+                min_size: e.flags.min_size,
+                max_size: e.flags.max_size,
+                feeds: Vec::new(),
+            };
+            Entry::from_parts(e.uid, e.id, e.query, flags, e.delivered)
+        })
+        .collect();
+    Ok(options().serialize(&(entries, profiles, history))?)
+}
+
+fn v5_to_v6(data: &[u8]) -> Result<Vec<u8>> {
+    let (entries, profiles, history): (Vec<Entry>, BTreeMap<u64, Profile>, Vec<Record>) =
+        options().deserialize(data)?;
+    // the ids of entries removed before can't be known, those were reused
+    let next_id = entries.iter().map(|e| e.id() + 1).max().unwrap_or(1);
+    Ok(options().serialize(&(entries, profiles, history, next_id))?)
+}
+
+fn v6_to_v7(data: &[u8]) -> Result<Vec<u8>> {
+    let (entries, profiles, _history, next_id): (
+        Vec<Entry>,
+        BTreeMap<u64, Profile>,
+        Vec<Record>,
+        u64,
+    ) = options().deserialize(data)?;
+    Ok(options().serialize(&(entries, profiles, next_id))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_migration() {
+        let legacy = vec![EntryV0 {
+            uid: 4,
+            patterns: vec!["Frieren".into(), "1080p".into()],
+        }];
+        let decoded = decode(&bincode::serialize(&legacy).unwrap()).unwrap();
+        assert!(decoded.is_outdated());
+        assert_eq!(decoded.contents.entries.len(), 1);
+        assert_eq!(
+            decoded.contents.entries[0].query().to_string(),
+            "Frieren AND 1080p"
+        );
+        assert_eq!(decoded.contents.entries[0].short_id(), "1");
+    }
+
+    #[test]
+    fn test_versioned_roundtrip() {
+        let query = Query::parse("Frieren").unwrap();
+        // stores written before the header are read as version 1
+        let v1 = vec![EntryV1 {
+            uid: 1,
+            query: query.clone(),
+            flags: FlagsV1::default(),
+            delivered: BTreeMap::new(),
+        }];
+        let unversioned = decode(&bincode::serialize(&v1).unwrap()).unwrap();
+        assert_eq!(unversioned.version, None);
+        assert_eq!(unversioned.contents.entries[0].query(), &query);
+        assert_eq!(unversioned.contents.entries[0].short_id(), "1");
+
+        let entries = unversioned.contents.entries;
+        let mut profiles = BTreeMap::new();
+        profiles.insert(1, Profile::default());
+        assert_eq!(unversioned.contents.next_id, 2);
+        let data = encode(&entries, &profiles, 7).unwrap();
+        let decoded = decode(&data).unwrap();
+        assert!(!decoded.is_outdated());
+        assert_eq!(decoded.contents.entries[0].uid(), 1);
+        assert_eq!(decoded.contents.profiles, profiles);
+        assert_eq!(decoded.contents.next_id, 7);
+
+        // the history of version 6 is kept for the file backend to move
+        let record = Record {
+            uid: 1,
+            subscriptions: vec!["Frieren".into()],
+            title: "Frieren - 01".into(),
+            link: String::new(),
+            magnet: None,
+            sent_at: 1_700_000_000,
+            status: crate::history::Status::Sent,
+        };
+        let mut v6 = MAGIC.to_vec();
+        v6.extend(6u16.to_le_bytes());
+        v6.extend(
+            options()
+                .serialize(&(&entries, &profiles, vec![record.clone()], 7u64))
+                .unwrap(),
+        );
+        let decoded = decode(&v6).unwrap();
+        assert!(decoded.is_outdated());
+        assert_eq!(decoded.contents.history, vec![record]);
+        assert_eq!(decoded.contents.next_id, 7);
+
+        let mut newer = data.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(decode(&newer).is_err());
+    }
+}
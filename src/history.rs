@@ -0,0 +1,252 @@
+use crate::persist;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+/// How many notifications are kept per user, older ones are dropped.
+pub const LIMIT_PER_USER: usize = 200;
+
+/// The file next to `user.bin` the file backend keeps the history in.
+pub const FILE_NAME: &str = "history.jsonl";
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum Status {
+    Sent,
+    /// The DM could not be sent, with the reason.
+    Failed(String),
+}
+
+impl Status {
+    pub fn parse(s: &str) -> Self {
+        match s.strip_prefix("failed: ") {
+            Some(reason) => Status::Failed(reason.to_string()),
+            None => Status::Sent,
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Sent => write!(f, "sent"),
+            Status::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+/// A notification that was delivered or tried to be.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Record {
+    pub uid: u64,
+    /// The user's subscriptions that matched.
+    pub subscriptions: Vec<String>,
+    pub title: String,
+    pub link: String,
+    /// `None` if the `.torrent` couldn't be resolved.
+    pub magnet: Option<String>,
+    /// Unix timestamp in seconds.
+    pub sent_at: i64,
+    pub status: Status,
+}
+
+impl Record {
+    pub fn sent_at(&self) -> Result<DateTime<Utc>> {
+        DateTime::from_timestamp(self.sent_at, 0).ok_or(anyhow!("invalid timestamp"))
+    }
+
+    /// One line for listings, with the time in the given timezone.
+    pub fn summary(&self, tz: &FixedOffset) -> String {
+        let at = match self.sent_at() {
+            Ok(at) => at.with_timezone(tz).format("%Y-%m-%d %H:%M").to_string(),
+            Err(_) => "?".into(),
+        };
+        match &self.status {
+            Status::Sent => format!("{at} {}", self.title),
+            status => format!("{at} {} ({status})", self.title),
+        }
+    }
+}
+
+/// The records within [`LIMIT_PER_USER`], kept per user so a new one
+/// doesn't have to look at the others' records.
+#[derive(Default)]
+pub struct History {
+    /// Oldest first.
+    users: HashMap<u64, VecDeque<Record>>,
+    len: usize,
+}
+
+impl History {
+    /// From records oldest first.
+    pub fn new(records: Vec<Record>) -> Self {
+        let mut history = History::default();
+        for record in records {
+            history.push(record);
+        }
+        history
+    }
+
+    /// Appends the record, dropping the user's oldest above [`LIMIT_PER_USER`].
+    pub fn push(&mut self, record: Record) {
+        let records = self.users.entry(record.uid).or_default();
+        records.push_back(record);
+        self.len += 1;
+        if records.len() > LIMIT_PER_USER {
+            records.pop_front();
+            self.len -= 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The user's records, oldest first.
+    pub fn user(&self, uid: u64) -> impl DoubleEndedIterator<Item = &Record> {
+        self.users.get(&uid).into_iter().flatten()
+    }
+
+    /// The records of all users, ordered by when they were sent.
+    pub fn all(&self) -> Vec<&Record> {
+        let mut all: Vec<&Record> = self.users.values().flatten().collect();
+        // stable, and per user they are in order already
+        all.sort_by_key(|r| r.sent_at);
+        all
+    }
+
+    pub fn to_vec(&self) -> Vec<Record> {
+        self.all().into_iter().cloned().collect()
+    }
+}
+
+/// Reads a history file, one JSON record per line, oldest first. Returns
+/// the records within [`LIMIT_PER_USER`] and how many lines the file has.
+pub fn read_file(path: &Path) -> Result<(Vec<Record>, usize)> {
+    if !persist::exists(path) {
+        return Ok((Vec::new(), 0));
+    }
+    persist::read_recover(path, parse)
+}
+
+pub fn parse(data: &[u8]) -> Result<(Vec<Record>, usize)> {
+    let lines: Vec<&[u8]> = data
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .collect();
+    let mut records = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_slice(line) {
+            Ok(record) => records.push(record),
+            // an append cut short by a crash
+            Err(e) if i + 1 == lines.len() => log::warn!("skipping the broken last record: {e}"),
+            Err(e) => bail!("record {}: {e}", i + 1),
+        }
+    }
+    Ok((limit(records), lines.len()))
+}
+
+/// Drops the records of each user above [`LIMIT_PER_USER`], oldest first.
+/// The file keeps them until it is rewritten.
+fn limit(records: Vec<Record>) -> Vec<Record> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    let mut kept: Vec<Record> = records
+        .into_iter()
+        .rev()
+        .filter(|r| {
+            let count = counts.entry(r.uid).or_default();
+            *count += 1;
+            *count <= LIMIT_PER_USER
+        })
+        .collect();
+    kept.reverse();
+    kept
+}
+
+/// Replaces the history file with the records.
+pub fn write_file(path: &Path, records: &[Record]) -> Result<()> {
+    persist::write_atomic(path, &lines(records)?)
+}
+
+/// Appends the records to the history file. A failed append is cut off
+/// again, so a retry doesn't leave a broken record in the middle.
+pub fn append_file(path: &Path, records: &[Record]) -> Result<()> {
+    let data = lines(records)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    if let Err(e) = file.write_all(&data).and_then(|()| file.sync_data()) {
+        let _ = file.set_len(len);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn lines(records: &[Record]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut data, record)?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::TempDir;
+
+    fn record(uid: u64, i: usize) -> Record {
+        Record {
+            uid,
+            subscriptions: vec!["Frieren".into()],
+            title: format!("Frieren - {i:02}"),
+            link: String::new(),
+            magnet: None,
+            sent_at: 1_700_000_000 + i as i64,
+            status: Status::Sent,
+        }
+    }
+
+    #[test]
+    fn test_history_file() {
+        let dir = TempDir::new("history-file");
+        let path = dir.join(FILE_NAME);
+        assert_eq!(read_file(&path).unwrap(), (Vec::new(), 0));
+        write_file(&path, &[record(1, 0)]).unwrap();
+        let more: Vec<Record> = (1..=LIMIT_PER_USER).map(|i| record(1, i)).collect();
+        append_file(&path, &more).unwrap();
+        append_file(&path, &[record(2, 0)]).unwrap();
+        // a crash in the middle of the next append
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"uid\": 3, \"subscr")
+            .unwrap();
+
+        let (records, lines) = read_file(&path).unwrap();
+        assert_eq!(lines, LIMIT_PER_USER + 3);
+        assert_eq!(records.len(), LIMIT_PER_USER + 1);
+        assert_eq!(records[0], record(1, 1));
+        assert_eq!(records[LIMIT_PER_USER], record(2, 0));
+        assert!(parse(b"{}\n{\"uid\": 1}\n").is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new((0..3).map(|i| record(2, i)).collect());
+        for i in 3..LIMIT_PER_USER + 4 {
+            history.push(record(1, i));
+        }
+        assert_eq!(history.len(), LIMIT_PER_USER + 3);
+        assert_eq!(history.user(1).next(), Some(&record(1, 4)));
+        assert_eq!(history.user(2).next_back(), Some(&record(2, 2)));
+        assert_eq!(history.user(3).count(), 0);
+        let all = history.to_vec();
+        assert_eq!(all[..3], [record(2, 0), record(2, 1), record(2, 2)]);
+        assert!(all.windows(2).all(|w| w[0].sent_at <= w[1].sent_at));
+    }
+}
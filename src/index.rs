@@ -0,0 +1,132 @@
+use crate::normalize::normalize;
+use crate::query::{Pattern, Query};
+use aho_corasick::AhoCorasick;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Narrows down which subscriptions can match a title before their
+/// queries are evaluated.
+///
+/// Every subscription is reduced to a set of literals of which at least one
+/// has to occur in a matching title. All literals go into one Aho-Corasick
+/// automaton, so finding candidates costs one pass over the title no matter
+/// how many subscriptions there are. Subscriptions without such literals
+/// (only regexes, fields or negations) are always candidates.
+#[derive(Default)]
+pub struct MatchIndex {
+    /// For subscriptions matched against the normalized title.
+    normalized: LiteralIndex,
+    /// For `--exact` subscriptions matched against the raw title.
+    exact: LiteralIndex,
+    always: Vec<usize>,
+}
+
+#[derive(Default)]
+struct LiteralIndex {
+    postings: HashMap<String, Vec<usize>>,
+    // built on first use after a change, literals share the automaton ids
+    automaton: OnceLock<Option<(AhoCorasick, Vec<String>)>>,
+}
+
+impl LiteralIndex {
+    fn insert(&mut self, literal: String, pos: usize) {
+        let posting = self.postings.entry(literal).or_default();
+        if posting.is_empty() {
+            self.automaton = OnceLock::new();
+        }
+        posting.push(pos);
+    }
+
+    fn remove(&mut self, pos: usize) {
+        let before = self.postings.len();
+        self.postings.retain(|_, posting| {
+            posting.retain(|p| *p != pos);
+            for p in posting.iter_mut().filter(|p| **p > pos) {
+                *p -= 1;
+            }
+            !posting.is_empty()
+        });
+        if self.postings.len() != before {
+            self.automaton = OnceLock::new();
+        }
+    }
+
+    fn candidates(&self, hay: &str, out: &mut Vec<usize>) {
+        let automaton = self.automaton.get_or_init(|| {
+            if self.postings.is_empty() {
+                return None;
+            }
+            let literals: Vec<String> = self.postings.keys().cloned().collect();
+            match AhoCorasick::new(&literals) {
+                Ok(ac) => Some((ac, literals)),
+                Err(e) => {
+                    log::error!("could not build match index: {e}");
+                    None
+                }
+            }
+        });
+        let Some((ac, literals)) = automaton else {
+            return;
+        };
+        let mut found = vec![false; literals.len()];
+        for m in ac.find_overlapping_iter(hay) {
+            let id = m.pattern().as_usize();
+            if !found[id] {
+                found[id] = true;
+                out.extend(&self.postings[&literals[id]]);
+            }
+        }
+    }
+}
+
+impl MatchIndex {
+    pub fn insert(&mut self, pos: usize, query: &Query, exact: bool) {
+        match required_literals(query) {
+            Some(literals) if exact => literals.into_iter().for_each(|l| self.exact.insert(l, pos)),
+            Some(literals) => literals
+                .into_iter()
+                .for_each(|l| self.normalized.insert(normalize(&l), pos)),
+            None => self.always.push(pos),
+        }
+    }
+
+    /// Drops the subscription at `pos`, later positions move down by one
+    /// like they do in the `Vec` of entries.
+    pub fn remove(&mut self, pos: usize) {
+        self.normalized.remove(pos);
+        self.exact.remove(pos);
+        self.always.retain(|p| *p != pos);
+        for p in self.always.iter_mut().filter(|p| **p > pos) {
+            *p -= 1;
+        }
+    }
+
+    /// Positions of subscriptions that may match, sorted and deduplicated.
+    pub fn candidates(&self, hay: &str, normalized_hay: &str) -> Vec<usize> {
+        let mut out = self.always.clone();
+        self.normalized.candidates(normalized_hay, &mut out);
+        self.exact.candidates(hay, &mut out);
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+/// Literals of which at least one occurs in every title matching the query,
+/// `None` if there is no such set.
+fn required_literals(query: &Query) -> Option<Vec<String>> {
+    match query {
+        Query::Term(Pattern::Substring(s)) if !s.is_empty() => Some(vec![s.clone()]),
+        Query::Term(_) | Query::Not(_) => None,
+        // any child's set works, fewer and longer literals give fewer candidates
+        Query::And(qs) => qs.iter().filter_map(required_literals).min_by_key(|ls| {
+            let shortest = ls.iter().map(String::len).min().unwrap_or(0);
+            (ls.len(), usize::MAX - shortest)
+        }),
+        Query::Or(qs) => qs
+            .iter()
+            .map(required_literals)
+            .collect::<Option<Vec<_>>>()
+            .map(|ls| ls.concat()),
+    }
+}
@@ -1,23 +1,42 @@
 use crate::message_handler::message_handler;
-use crate::notify::eval_entry;
+use crate::notify::{eval_entry, DEFERRED_FILE};
 use crate::rss::poll_rss;
-use anyhow::bail;
+use crate::setup::{FeedsKey, ImportsKey, SnapshotsKey, StoreKey};
+use crate::snapshot::{snapshot_loop, Retention, Snapshots};
+use crate::store::StoreHandle;
+use anyhow::{anyhow, bail};
 #[allow(deprecated)]
 use serenity::all::standard::Configuration;
 #[allow(deprecated)]
-use serenity::all::{Message, StandardFramework};
+use serenity::all::{Http, Message, StandardFramework};
 use serenity::async_trait;
 use serenity::prelude::*;
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinSet;
 
+mod feed;
+mod format;
+mod history;
+mod index;
 mod message_handler;
+mod normalize;
 mod notify;
+mod persist;
+mod profile;
+mod query;
+mod release;
 mod rss;
+mod seen;
 mod setup;
+mod snapshot;
+mod sqlite;
+mod storage;
 mod store;
 mod torrent;
+mod transfer;
 
 struct Handler;
 
@@ -36,24 +55,86 @@ impl EventHandler for Handler {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     simple_logger::init_with_level(log::Level::Warn)?;
-    let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN");
-    let rss = env::var("RSS_URL").expect("RSS_URL");
-    let check_val = env::var("CHECK_VAL").unwrap_or("60".into());
-    let failure_val = env::var("FAILURE_VAL").unwrap_or("180".into());
     let binding = PathBuf::from(env::var("STORE_FOLDER_PATH").unwrap_or("~/.makima".into()));
     let store_path = plain_path::plain(&binding)?;
+    let backend = env::var("STORE_BACKEND").unwrap_or("file".into());
+    let snapshot_interval = env::var("SNAPSHOT_INTERVAL").unwrap_or("3600".into());
+    let retention = Retention {
+        keep: env::var("SNAPSHOT_KEEP").unwrap_or("24".into()).parse()?,
+        keep_days: env::var("SNAPSHOT_KEEP_DAYS")
+            .unwrap_or("7".into())
+            .parse()?,
+    };
+    let snapshots = Arc::new(Snapshots::new(
+        store_path.to_path_buf(),
+        &backend,
+        retention,
+    ));
+
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("snapshots") => {
+            for s in snapshots.list()? {
+                println!("{}\t{} bytes", s.name, s.bytes);
+            }
+            return Ok(());
+        }
+        Some("restore") => {
+            let name = args
+                .next()
+                .ok_or(anyhow!("use makima restore <snapshot>"))?;
+            let before = snapshots.restore_files(&name)?;
+            println!("restored {name}, the previous state is in snapshot {before}");
+            return Ok(());
+        }
+        Some(other) => bail!("unknown command {other}, use snapshots or restore <snapshot>"),
+        None => {}
+    }
 
-    setup::setup_resources(&store_path)?;
+    let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN");
+    let check_val = Duration::from_secs(env::var("CHECK_VAL").unwrap_or("60".into()).parse()?);
+    let failure_val = Duration::from_secs(env::var("FAILURE_VAL").unwrap_or("180".into()).parse()?);
+    let feeds = match env::var("RSS_FEEDS") {
+        Ok(file) => feed::parse_feeds(&std::fs::read(file)?, check_val, failure_val)?,
+        Err(_) => {
+            let rss = env::var("RSS_URL").expect("RSS_URL or RSS_FEEDS");
+            vec![feed::single_feed(&rss, check_val, failure_val)?]
+        }
+    };
+    let hold_window = env::var("HOLD_WINDOW").unwrap_or("600".into());
+
+    // a deploy may upgrade or break the files, keep them as they were
+    if store_path.exists() {
+        snapshots.create(chrono::Utc::now())?;
+        // restarts add up too, not only the periodic snapshots
+        snapshots.prune()?;
+    }
+    let store: StoreHandle = Arc::new(RwLock::new(setup::setup_resources(
+        &store_path,
+        &backend,
+        &feeds,
+    )?));
 
     let (send, rec) = tokio::sync::mpsc::channel(3);
-    let polling_loop_handle = tokio::spawn(poll_rss(
-        rss,
-        store_path.to_str().unwrap().to_string(),
-        Duration::from_secs(check_val.parse()?),
-        Duration::from_secs(failure_val.parse()?),
-        send,
+    let feeds = Arc::new(feeds);
+    let mut polling_loops = JoinSet::new();
+    for feed in feeds.iter().cloned() {
+        polling_loops.spawn(poll_rss(feed, store_path.to_path_buf(), send.clone()));
+    }
+    let http = Arc::new(Http::new(&token));
+    let eval_loop_handle = tokio::spawn(eval_entry(
+        rec,
+        Arc::clone(&store),
+        http,
+        Duration::from_secs(hold_window.parse()?),
+        store_path.join(DEFERRED_FILE),
+    ));
+
+    let snapshot_loop_handle = tokio::spawn(snapshot_loop(
+        Arc::clone(&snapshots),
+        Arc::clone(&store),
+        Duration::from_secs(snapshot_interval.parse()?),
     ));
-    let eval_loop_handle = tokio::spawn(eval_entry(rec));
 
     let framework = StandardFramework::new();
     framework.configure(Configuration::new().no_dm_prefix(true));
@@ -65,22 +146,47 @@ async fn main() -> anyhow::Result<()> {
     )
     .event_handler(Handler)
     .framework(framework)
+    .type_map_insert::<StoreKey>(Arc::clone(&store))
+    .type_map_insert::<SnapshotsKey>(snapshots)
+    .type_map_insert::<FeedsKey>(feeds)
+    .type_map_insert::<ImportsKey>(Default::default())
     .await
     .expect("Error creating client");
 
     let client_handle = tokio::spawn(async move { client.start().await });
 
-    tokio::select! {
-        _ = polling_loop_handle => {
-            log::error!("polling loop died");
-        }
-        _ = eval_loop_handle => {
-            log::error!("eval loop died");
-        }
-        _ = client_handle => {
-            log::error!("client died");
-        }
+    let died = tokio::select! {
+        _ = polling_loops.join_next() => Some("polling loop"),
+        _ = eval_loop_handle => Some("eval loop"),
+        _ = client_handle => Some("client"),
+        _ = snapshot_loop_handle => Some("snapshot loop"),
+        _ = shutdown_signal() => None,
+    };
+    if let Some(loop_name) = died {
+        log::error!("{loop_name} died");
+    }
+
+    // changes may still be waiting to be written
+    if let Err(e) = store.write().await.flush() {
+        log::error!("writing the store on shutdown failed: {e}");
     }
 
-    bail!("one of the loops died");
+    if died.is_some() {
+        bail!("one of the loops died");
+    }
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -1,7 +1,13 @@
-use crate::setup::get_user_store;
-use crate::store::Entry;
+use crate::feed;
+use crate::query::Query;
+use crate::setup::{feeds, pending_imports, snapshots, user_store};
+use crate::store::{parse_id, Entry, Flags};
+use crate::torrent::format_size;
+use crate::transfer;
 use anyhow::{anyhow, Result};
-use serenity::all::{Context, Message};
+use chrono::FixedOffset;
+use serenity::all::{Context, CreateAttachment, CreateMessage, Message};
+use std::env;
 
 pub async fn message_handler(ctx: Context, msg: Message) -> Result<()> {
     let (op, arg) = split_at_fist_space(&msg.content);
@@ -11,8 +17,17 @@ pub async fn message_handler(ctx: Context, msg: Message) -> Result<()> {
         ("help", _) => help(ctx, msg).await,
         ("add", ident) => add(ctx, msg, ident).await,
         ("list", _) => list_patterns(ctx, msg).await,
+        ("feeds", _) => list_feeds(ctx, msg).await,
         ("remove", "all") => remove_all(ctx, msg).await,
         ("remove", ident) => remove(ctx, msg, ident).await,
+        ("settings", changes) => settings(ctx, msg, changes).await,
+        ("history", n) => history(ctx, msg, n).await,
+        ("export", _) => export(ctx, msg).await,
+        ("import", mode) => import(ctx, msg, mode).await,
+        ("lookup", title) => lookup(ctx, msg, title).await,
+        ("status", _) => status(ctx, msg).await,
+        ("snapshots", _) => list_snapshots(ctx, msg).await,
+        ("restore", name) => restore(ctx, msg, name).await,
         _ => Err(anyhow!(
             "Unknown Command. Check available commands with `help`."
         )),
@@ -28,9 +43,34 @@ async fn help(ctx: Context, msg: Message) -> Result<()> {
     msg.reply(
         ctx,
         "```Usage:\n\
-              add pat\t\tchecks new releases for pat and notifies you about them\n\
-              list\t\tlists all your patterns with their corresponding index\n\
-              remove index|all\t\tremoves the pattern at that index or all of them\n\
+              add query\t\tchecks new releases for query and notifies you about them\n\
+              \t\tcombine terms with AND (or ;), OR, NOT (or -) and parentheses\n\
+              \t\te.g. (SubsPlease OR Erai-raws) AND Frieren AND 1080p AND -Batch\n\
+              \t\tfilter on parsed fields with group:, show:, ep:, v:, res:, codec:, crc:\n\
+              \t\te.g. group:SubsPlease res:1080 show:\"Frieren\" ep:>12\n\
+              \t\tquote terms with \"...\", use re:\"...\" for a regex on the unmodified title\n\
+              \t\tflags go in front of the query, e.g. add --exact --renotify query\n\
+              \t\t--exact\tdon't ignore case, accents and ._ separators\n\
+              \t\t--renotify\talso notify for v2 or REPACK of an episode you already got\n\
+              \t\t--prefer=Group1,Group2\tonly send the release of the most preferred group per episode\n\
+              \t\t--min-size=500MB --max-size=2GB\tonly releases with a payload in that range\n\
+              \t\t--feed=name1,name2\tonly releases from these feeds, see feeds\n\
+              \t\tolder or already delivered episodes of a show are skipped\n\
+              list\t\tlists all your patterns with their id\n\
+              feeds\t\tlists the feeds releases come from\n\
+              remove id|all\t\tremoves the pattern with that id or all of them\n\
+              settings [key=value ...]\t\tshows or changes your settings\n\
+              \t\ttimezone=UTC+2\ttimes in DMs and for scheduling\n\
+              \t\tformat=embed|plain\n\
+              \t\tdigest=off|hourly|18:00\tbundle DMs and send them hourly or daily\n\
+              \t\tquiet=22-7|off\tno DMs between these hours, they are sent afterwards\n\
+              \t\tlocale=en|de\n\
+              \t\tredirect=default|off|https://...\twhere the download link points to\n\
+              history [n]\t\tshows the last n (10) notifications you got, with their download links\n\
+              export\t\tsends your subscriptions and settings as a file\n\
+              import [merge|replace]\t\tupload an exported file with this message to see what changes\n\
+              \t\tmerge (default) adds subscriptions you don't have, replace drops all others\n\
+              import confirm|cancel\t\tapplies or discards the uploaded file\n\
               help\t\tshows this message```",
     )
         .await?;
@@ -38,30 +78,34 @@ async fn help(ctx: Context, msg: Message) -> Result<()> {
 }
 
 async fn add(ctx: Context, msg: Message, pat: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
     let user_id = msg.author.id.get();
-    let mut store = get_user_store().write().await;
-    let new_entry = Entry::new(user_id,
-                               pat.to_string()
-                                   .split(';')
-                                      .map(|s| s.to_string())
-                                   .collect());
-    store.add(new_entry)?;
+    let (flags, pat) = split_flags(pat)?;
+    let query = Query::parse(pat)?;
+    feed::check_known(&flags.feeds, &feeds(&ctx).await?)?;
+    let mut store = handle.write().await;
+    let new_entry = Entry::new(user_id, query, flags);
+    let id = store.add(new_entry)?;
     drop(store);
-    msg.reply(ctx, "pattern added").await?;
+    msg.reply(ctx, format!("pattern added with id {id}"))
+        .await?;
     Ok(())
 }
 
 async fn list_patterns(ctx: Context, msg: Message) -> Result<()> {
+    let handle = user_store(&ctx).await?;
     let user_id = msg.author.id.get();
-    let store = get_user_store().read().await;
+    let store = handle.read().await;
     let user_patterns = store.get_elements_for_user(user_id);
     drop(store);
     let msg_text = format!(
         "```{}\n```",
         user_patterns
             .into_iter()
-            .enumerate()
-            .map(|(i, p)| format!("{i}\t\t{}", p.join("\t")))
+            .map(|e| match e.flags().to_string().as_str() {
+                "" => format!("{}\t\t{}", e.short_id(), e.query()),
+                flags => format!("{}\t\t{flags} {}", e.short_id(), e.query()),
+            })
             .collect::<Vec<String>>()
             .join("\n")
     );
@@ -69,25 +113,273 @@ async fn list_patterns(ctx: Context, msg: Message) -> Result<()> {
     Ok(())
 }
 
+async fn list_feeds(ctx: Context, msg: Message) -> Result<()> {
+    let feeds = feeds(&ctx).await?;
+    let names: Vec<&str> = feeds.iter().map(|f| f.name.as_str()).collect();
+    msg.reply(ctx, format!("```{}```", names.join("\n")))
+        .await?;
+    Ok(())
+}
+
 async fn remove_all(ctx: Context, msg: Message) -> Result<()> {
+    let handle = user_store(&ctx).await?;
     let user_id = msg.author.id.get();
-    let mut store = get_user_store().write().await;
+    let mut store = handle.write().await;
     store.remove_user(user_id)?;
     drop(store);
     msg.reply(ctx, "successfully removed all patterns").await?;
     Ok(())
 }
 
-async fn remove(ctx: Context, msg: Message, index: &str) -> Result<()> {
-    let index = index.parse()?;
+async fn remove(ctx: Context, msg: Message, id: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    let id = parse_id(id)?;
     let user_id = msg.author.id.get();
-    let mut store = get_user_store().write().await;
-    store.remove_by_index(user_id, index)?;
+    let mut store = handle.write().await;
+    store.remove_by_id(user_id, id)?;
     drop(store);
     msg.reply(ctx, "successfully removed pattern").await?;
     Ok(())
 }
 
+async fn settings(ctx: Context, msg: Message, changes: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    let user_id = msg.author.id.get();
+    let mut store = handle.write().await;
+    let mut profile = store.profile(user_id);
+    if !changes.trim().is_empty() {
+        for change in changes.split_whitespace() {
+            let (key, value) = change
+                .split_once('=')
+                .ok_or(anyhow!("use settings key=value, e.g. settings quiet=22-7"))?;
+            profile.set(key, value)?;
+        }
+        store.set_profile(user_id, profile.clone())?;
+    }
+    drop(store);
+    msg.reply(ctx, format!("```{profile}```")).await?;
+    Ok(())
+}
+
+async fn history(ctx: Context, msg: Message, n: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    let n = match n.trim() {
+        "" => 10,
+        n => n
+            .parse::<usize>()
+            .map_err(|_| anyhow!("use history or history 20"))?
+            .min(50),
+    };
+    let user_id = msg.author.id.get();
+    let store = handle.read().await;
+    let profile = store.profile(user_id);
+    let lines: Vec<String> = store
+        .history(user_id, n)
+        .into_iter()
+        .map(|r| {
+            let mut line = format!("{}\n<{}>", r.summary(&profile.timezone()), r.link);
+            if let Some(magnet) = &r.magnet {
+                let link = profile.magnet_link(magnet);
+                line += &format!(" [{}]({link})", profile.labels().download);
+            }
+            line
+        })
+        .collect();
+    drop(store);
+    if lines.is_empty() {
+        msg.reply(ctx, "you didn't get any notifications yet")
+            .await?;
+        return Ok(());
+    }
+    reply_chunked(&ctx, &msg, lines).await
+}
+
+/// Finds notifications of all users by title, for debugging missed pings.
+async fn lookup(ctx: Context, msg: Message, title: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    if !is_admin(&msg) {
+        return Err(anyhow!("lookup is only available to admins"));
+    }
+    if title.trim().is_empty() {
+        return Err(anyhow!("use lookup title"));
+    }
+    let store = handle.read().await;
+    let utc = FixedOffset::east_opt(0).unwrap();
+    let lines: Vec<String> = store
+        .lookup(title, 20)
+        .into_iter()
+        .map(|r| {
+            let matched: Vec<String> = r.subscriptions.iter().map(|s| format!("`{s}`")).collect();
+            format!("{} <@{}> {}", r.summary(&utc), r.uid, matched.join(", "))
+        })
+        .collect();
+    drop(store);
+    if lines.is_empty() {
+        msg.reply(ctx, "no notifications with that title").await?;
+        return Ok(());
+    }
+    reply_chunked(&ctx, &msg, lines).await
+}
+
+async fn status(ctx: Context, msg: Message) -> Result<()> {
+    if !is_admin(&msg) {
+        return Err(anyhow!("status is only available to admins"));
+    }
+    let handle = user_store(&ctx).await?;
+    let status = handle
+        .read()
+        .await
+        .storage_status()
+        .unwrap_or("the store is written synchronously".into());
+    msg.reply(ctx, format!("```{status}```")).await?;
+    Ok(())
+}
+
+async fn list_snapshots(ctx: Context, msg: Message) -> Result<()> {
+    if !is_admin(&msg) {
+        return Err(anyhow!("snapshots is only available to admins"));
+    }
+    let lines: Vec<String> = snapshots(&ctx)
+        .await?
+        .list()?
+        .into_iter()
+        .map(|s| format!("{}\t{}", s.name, format_size(s.bytes)))
+        .collect();
+    if lines.is_empty() {
+        msg.reply(ctx, "there are no snapshots yet").await?;
+        return Ok(());
+    }
+    reply_chunked(&ctx, &msg, lines).await
+}
+
+async fn restore(ctx: Context, msg: Message, name: &str) -> Result<()> {
+    if !is_admin(&msg) {
+        return Err(anyhow!("restore is only available to admins"));
+    }
+    if name.trim().is_empty() {
+        return Err(anyhow!("use restore name, see snapshots for the names"));
+    }
+    let handle = user_store(&ctx).await?;
+    let before = snapshots(&ctx).await?.restore(name.trim(), &handle).await?;
+    msg.reply(
+        ctx,
+        format!("restored {name}, the state before is in snapshot {before}"),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether the author is listed in `ADMIN_ID`.
+fn is_admin(msg: &Message) -> bool {
+    let admins = env::var("ADMIN_ID").unwrap_or_default();
+    let user_id = msg.author.id.get().to_string();
+    admins.split(',').any(|a| a.trim() == user_id)
+}
+
+async fn export(ctx: Context, msg: Message) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    let user_id = msg.author.id.get();
+    let store = handle.read().await;
+    let data = transfer::export(
+        &store.get_elements_for_user(user_id),
+        &store.profile(user_id),
+    )?;
+    drop(store);
+    let file = CreateAttachment::bytes(data, "makima.json");
+    let reply = CreateMessage::new()
+        .content("your subscriptions and settings, upload the file with `import` to restore them")
+        .add_file(file)
+        .reference_message(&msg);
+    msg.channel_id.send_message(&ctx, reply).await?;
+    Ok(())
+}
+
+async fn import(ctx: Context, msg: Message, mode: &str) -> Result<()> {
+    let handle = user_store(&ctx).await?;
+    let pending = pending_imports(&ctx).await?;
+    let user_id = msg.author.id.get();
+    let replace = match mode.trim() {
+        "" | "merge" => false,
+        "replace" => true,
+        "cancel" => {
+            let text = match pending.take(user_id) {
+                Some(_) => "import discarded",
+                None => "there is no import to discard",
+            };
+            msg.reply(ctx, text).await?;
+            return Ok(());
+        }
+        "confirm" => {
+            let import = pending.take(user_id).ok_or(anyhow!(
+                "no import is waiting, uploads expire after 15 minutes"
+            ))?;
+            let mut store = handle.write().await;
+            let added = import.apply(&mut store, user_id)?;
+            drop(store);
+            msg.reply(ctx, format!("import done, {added} subscriptions added"))
+                .await?;
+            return Ok(());
+        }
+        _ => {
+            return Err(anyhow!(
+                "use import, import replace, import confirm or import cancel"
+            ))
+        }
+    };
+    let attachment = msg
+        .attachments
+        .first()
+        .ok_or(anyhow!("attach the file from `export` to the message"))?;
+    if attachment.size > transfer::MAX_FILE_SIZE {
+        return Err(anyhow!("the file is too big"));
+    }
+    let data = attachment.download().await?;
+    let import = transfer::Import::parse(&data, replace, &feeds(&ctx).await?)?;
+    let store = handle.read().await;
+    let preview = import.preview(
+        &store.get_elements_for_user(user_id),
+        &store.profile(user_id),
+    );
+    drop(store);
+    pending.stage(user_id, import);
+    let text = format!("```{preview}```reply `import confirm` to apply or `import cancel`");
+    reply_chunked(&ctx, &msg, vec![text]).await
+}
+
+/// Replies with the lines, split into as many messages as the 2000
+/// character limit needs.
+async fn reply_chunked(ctx: &Context, msg: &Message, lines: Vec<String>) -> Result<()> {
+    let mut messages = vec![String::new()];
+    for line in lines {
+        let line: String = line.chars().take(2000).collect();
+        let last = messages.last_mut().unwrap();
+        if !last.is_empty() && last.chars().count() + line.chars().count() + 1 > 2000 {
+            messages.push(String::new());
+        }
+        let last = messages.last_mut().unwrap();
+        if !last.is_empty() {
+            last.push('\n');
+        }
+        last.push_str(&line);
+    }
+    for m in messages {
+        msg.reply(ctx, m).await?;
+    }
+    Ok(())
+}
+
+/// Splits leading `--flag`s off the argument of `add`.
+fn split_flags(arg: &str) -> Result<(Flags, &str)> {
+    let mut flags = Flags::default();
+    let mut rest = arg.trim_start();
+    while let Some(flag) = rest.strip_prefix("--") {
+        let (name, tail) = flag.split_once(' ').unwrap_or((flag, ""));
+        flags.set(name)?;
+        rest = tail.trim_start();
+    }
+    Ok((flags, rest))
+}
+
 fn split_at_fist_space(command: &str) -> (String, String) {
     let mut operand = Vec::new();
     let mut argument = Vec::new();
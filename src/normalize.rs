@@ -0,0 +1,45 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Brings titles and patterns into a common form so that `one piece`
+/// matches `One.Piece`, `ＯＮＥ ＰＩＥＣＥ` or `【One Piece】`.
+///
+/// Leading and trailing whitespace is kept so patterns like `One ` keep
+/// their word boundary meaning.
+pub fn normalize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut last_space = false;
+    for c in s.nfkc().flat_map(char::to_lowercase) {
+        let c = match c {
+            '_' | '.' => ' ',
+            '(' | '{' | '【' | '〔' | '「' | '『' | '〈' | '《' => '[',
+            ')' | '}' | '】' | '〕' | '」' | '』' | '〉' | '》' => ']',
+            c if c.is_whitespace() => ' ',
+            c => c,
+        };
+        if c == ' ' {
+            if last_space {
+                continue;
+            }
+            last_space = true;
+        } else {
+            last_space = false;
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("One.Piece"), "one piece");
+        assert_eq!(normalize("ＯＮＥ　ＰＩＥＣＥ"), "one piece");
+        assert_eq!(normalize("Frieren__-  12 (1080p)"), "frieren - 12 [1080p]");
+        assert_eq!(normalize("【SubsPlease】"), "[subsplease]");
+        assert_eq!(normalize("Pokémon"), normalize("Poke\u{301}mon"));
+        assert_eq!(normalize("One "), "one ");
+    }
+}
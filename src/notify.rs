@@ -1,60 +1,943 @@
+use crate::history::{Record, Status};
+use crate::persist;
+use crate::profile::{Format, Profile};
+use crate::release::ReleaseInfo;
 use crate::rss::RssEntry;
-use crate::setup::get_user_store;
+use crate::store::{Claim, Flags, Hold, StoreHandle};
+use crate::torrent::{format_size, ResolvedTorrent};
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serenity::all::{CreateEmbed, CreateMessage, Http, UserId};
-use std::env;
+use serenity::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinSet;
+use tokio::time::Instant;
 
-pub async fn eval_entry(mut receiver: Receiver<Vec<RssEntry>>) -> Result<()> {
+/// Sends DMs, Discord's HTTP API in the bot.
+#[async_trait]
+pub trait Messenger: Send + Sync {
+    async fn dm(&self, user: u64, message: CreateMessage) -> Result<()>;
+}
+
+#[async_trait]
+impl Messenger for Http {
+    async fn dm(&self, user: u64, message: CreateMessage) -> Result<()> {
+        let channel = UserId::from(user).create_dm_channel(self).await?;
+        channel.send_message(self, message).await?;
+        Ok(())
+    }
+}
+
+/// A feed item together with what we got from its `.torrent`.
+struct Release {
+    entry: RssEntry,
+    torrent: Result<ResolvedTorrent>,
+}
+
+/// Releases of one episode collected for a user with a group preference
+/// until the hold window ends.
+struct Held {
+    deadline: Instant,
+    /// The user's subscriptions waiting for this episode, each ranks the
+    /// releases by its own preference.
+    holds: Vec<(Hold, Flags)>,
+    releases: Vec<Arc<Release>>,
+    /// Links the user already got through subscriptions without a hold.
+    sent: HashSet<String>,
+}
+
+/// User, show and episode.
+type HeldKey = (u64, String, u32);
+
+/// A user and those of their subscriptions that matched a release.
+type Recipient = (u64, Vec<String>);
+
+/// The release some of the held subscriptions prefer.
+struct Choice {
+    release: Arc<Release>,
+    holds: Vec<Hold>,
+    /// The other releases, best first for the first of the subscriptions.
+    alternatives: Vec<Arc<Release>>,
+}
+
+/// What one DM is about, several of them make a digest.
+struct Notification {
+    release: Arc<Release>,
+    subscriptions: Vec<String>,
+    alternatives: Vec<String>,
+}
+
+/// Notifications held back by the user's quiet hours or digest setting.
+struct Deferred {
+    deadline: Instant,
+    /// The deadline as a time that means something after a restart.
+    at: DateTime<Utc>,
+    notifications: Vec<Notification>,
+}
+
+/// File in the store folder with the deferred notifications, their
+/// episodes are already recorded as delivered so they mustn't get lost.
+pub const DEFERRED_FILE: &str = "deferred.json";
+
+#[derive(Serialize, Deserialize)]
+struct SavedDeferred {
+    user: u64,
+    at: i64,
+    notifications: Vec<SavedNotification>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedNotification {
+    id: String,
+    feed: String,
+    title: String,
+    link: String,
+    pub_date: String,
+    /// The error message if the `.torrent` couldn't be fetched.
+    torrent: std::result::Result<ResolvedTorrent, String>,
+    subscriptions: Vec<String>,
+    alternatives: Vec<String>,
+}
+
+/// The deferred notifications by user.
+#[derive(Default)]
+struct DeferredQueue {
+    users: HashMap<u64, Deferred>,
+    /// Whether they changed since they were last saved.
+    dirty: bool,
+}
+
+impl DeferredQueue {
+    fn push(&mut self, user: u64, at: DateTime<Utc>, notification: Notification) {
+        let wait = (at - Utc::now()).to_std().unwrap_or_default();
+        self.users
+            .entry(user)
+            .or_insert_with(|| Deferred {
+                deadline: Instant::now() + wait,
+                at,
+                notifications: Vec::new(),
+            })
+            .notifications
+            .push(notification);
+        self.dirty = true;
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.users.values().map(|d| d.deadline).min()
+    }
+
+    /// Removes the notifications of the users whose deadline passed.
+    fn take_due(&mut self, now: Instant) -> Vec<(u64, Deferred)> {
+        let users: Vec<u64> = self
+            .users
+            .iter()
+            .filter(|(_, d)| d.deadline <= now)
+            .map(|(u, _)| *u)
+            .collect();
+        self.dirty |= !users.is_empty();
+        users
+            .into_iter()
+            .map(|u| (u, self.users.remove(&u).unwrap()))
+            .collect()
+    }
+
+    fn save(&mut self, path: &Path) -> Result<()> {
+        let saved: Vec<SavedDeferred> = self
+            .users
+            .iter()
+            .map(|(user, d)| SavedDeferred {
+                user: *user,
+                at: d.at.timestamp(),
+                notifications: d
+                    .notifications
+                    .iter()
+                    .map(|n| {
+                        let entry = &n.release.entry;
+                        SavedNotification {
+                            id: entry.id.clone(),
+                            feed: entry.feed.clone(),
+                            title: entry.title.clone(),
+                            link: entry.link.clone(),
+                            pub_date: entry.pub_date.to_rfc3339(),
+                            torrent: match &n.release.torrent {
+                                Ok(t) => Ok(ResolvedTorrent {
+                                    magnet: t.magnet.clone(),
+                                    size: t.size,
+                                    file_count: t.file_count,
+                                }),
+                                Err(e) => Err(e.to_string()),
+                            },
+                            subscriptions: n.subscriptions.clone(),
+                            alternatives: n.alternatives.clone(),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        persist::write_atomic(path, &serde_json::to_vec(&saved)?)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Loads the file at `path`. If it is unreadable it is moved aside, so
+    /// saving doesn't overwrite what may still be recovered by hand.
+    fn open(path: &Path) -> Result<Self> {
+        match Self::load(path) {
+            Ok(queue) => Ok(queue),
+            Err(e) => {
+                let mut aside = path.as_os_str().to_owned();
+                aside.push(".unreadable");
+                std::fs::rename(path, &aside)?;
+                log::error!(
+                    "could not load the deferred notifications ({e}), moved them to {}",
+                    Path::new(&aside).display()
+                );
+                Ok(Self::default())
+            }
+        }
+    }
+
+    /// Deadlines that passed while the bot was down are due right away.
+    fn load(path: &Path) -> Result<Self> {
+        if !persist::exists(path) {
+            return Ok(Self::default());
+        }
+        let saved: Vec<SavedDeferred> =
+            persist::read_recover(path, |d| Ok(serde_json::from_slice(d)?))?;
+        let now = Utc::now();
+        let mut deferred = HashMap::new();
+        for d in saved {
+            let at = DateTime::from_timestamp(d.at, 0).ok_or(anyhow!("bad deadline {}", d.at))?;
+            let mut notifications = Vec::new();
+            for n in d.notifications {
+                let entry = RssEntry {
+                    id: n.id,
+                    feed: n.feed,
+                    release: ReleaseInfo::parse(&n.title),
+                    title: n.title,
+                    link: n.link,
+                    pub_date: DateTime::parse_from_rfc3339(&n.pub_date)?,
+                };
+                notifications.push(Notification {
+                    release: Arc::new(Release {
+                        entry,
+                        torrent: n.torrent.map_err(|e| anyhow!(e)),
+                    }),
+                    subscriptions: n.subscriptions,
+                    alternatives: n.alternatives,
+                });
+            }
+            let wait = (at - now).to_std().unwrap_or_default();
+            deferred.insert(
+                d.user,
+                Deferred {
+                    deadline: Instant::now() + wait,
+                    at,
+                    notifications,
+                },
+            );
+        }
+        Ok(Self {
+            users: deferred,
+            dirty: false,
+        })
+    }
+}
+
+pub async fn eval_entry(
+    mut receiver: Receiver<Vec<RssEntry>>,
+    store: StoreHandle,
+    messenger: Arc<dyn Messenger>,
+    hold_window: Duration,
+    deferred_path: PathBuf,
+) -> Result<()> {
+    let mut held: HashMap<HeldKey, Held> = HashMap::new();
+    let (mut deferred, save) = match DeferredQueue::open(&deferred_path) {
+        Ok(deferred) => (deferred, true),
+        Err(e) => {
+            log::error!("could not move the unreadable deferred notifications aside, new ones won't be saved: {e}");
+            (DeferredQueue::default(), false)
+        }
+    };
     loop {
-        let entries = receiver.recv().await.ok_or(anyhow!("channel died"))?;
-        for entry in entries {
-            // we don't want to get rate limited when scraping
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            notify_users(entry).await?;
+        let deadline = held
+            .values()
+            .map(|h| h.deadline)
+            .chain(deferred.next_deadline())
+            .min();
+        let expired = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            entries = receiver.recv() => {
+                let entries = entries.ok_or(anyhow!("channel died"))?;
+                for entry in entries {
+                    // we don't want to get rate limited when scraping
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    let title = entry.title.clone();
+                    // a failed write of the store mustn't stop notifications
+                    if let Err(e) = notify_users(&store, &messenger, entry, &mut held, &mut deferred, hold_window).await {
+                        log::error!("could not notify about {title}: {e}");
+                    }
+                }
+            }
+            _ = expired => {
+                let now = Instant::now();
+                let keys: Vec<HeldKey> = held
+                    .iter()
+                    .filter(|(_, h)| h.deadline <= now)
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for key in keys {
+                    let h = held.remove(&key).unwrap();
+                    if let Err(e) = notify_held(&store, &messenger, key.0, h, &mut deferred).await {
+                        log::error!("could not notify {} about episode {} of {}: {e}", key.0, key.2, key.1);
+                    }
+                }
+                for (user, d) in deferred.take_due(now) {
+                    if let Err(e) = notify_deferred(&store, &messenger, user, d).await {
+                        log::error!("could not send the deferred notifications of {user}: {e}");
+                    }
+                }
+            }
+        }
+        if save && deferred.dirty {
+            if let Err(e) = deferred.save(&deferred_path) {
+                log::error!("could not save the deferred notifications: {e}");
+            }
         }
     }
 }
 
-async fn notify_users(entry: RssEntry) -> Result<()> {
-    let user_store = get_user_store().read().await;
-    let users_to_notify = user_store.get_users_matching(&entry.title);
+async fn notify_users(
+    store: &StoreHandle,
+    messenger: &Arc<dyn Messenger>,
+    entry: RssEntry,
+    held: &mut HashMap<HeldKey, Held>,
+    deferred: &mut DeferredQueue,
+    hold_window: Duration,
+) -> Result<()> {
+    let user_store = store.read().await;
+    let has_matches = user_store.has_matches(&entry.feed, &entry.title, &entry.release);
     drop(user_store);
-    if !users_to_notify.is_empty() {
-        let magnet = match entry.get_magnet_for_entry().await {
-            Ok(m) => m,
-            Err(e) => {
-                println!("{}", &e);
-                format!("{e}")
+    if !has_matches {
+        return Ok(());
+    }
+    let torrent = entry.resolve_torrent().await;
+    if let Err(e) = &torrent {
+        log::error!("could not resolve torrent of {}: {e}", entry.title);
+    }
+    let size = torrent.as_ref().ok().map(|t| t.size);
+    let mut user_store = store.write().await;
+    let claims = user_store.claim_matching(&entry.feed, &entry.title, &entry.release, size);
+    drop(user_store);
+    let release = Arc::new(Release { entry, torrent });
+
+    let (users_to_notify, holds) = group_claims(claims);
+    for (hold, flags) in holds {
+        let key = (hold.uid, hold.show.clone(), hold.episode);
+        let h = held.entry(key).or_insert_with(|| Held {
+            deadline: Instant::now() + hold_window,
+            holds: Vec::new(),
+            releases: Vec::new(),
+            sent: HashSet::new(),
+        });
+        if users_to_notify.iter().any(|(u, _)| *u == hold.uid) {
+            h.sent.insert(release.entry.link.clone());
+        }
+        if !h.releases.iter().any(|r| Arc::ptr_eq(r, &release)) {
+            h.releases.push(Arc::clone(&release));
+        }
+        if !h.holds.iter().any(|(h, _)| *h == hold) {
+            h.holds.push((hold, flags));
+        }
+    }
+    send(
+        store,
+        messenger,
+        release,
+        users_to_notify,
+        Vec::new(),
+        deferred,
+    )
+    .await
+}
+
+/// Splits the claims into the users to DM right away, each with all of
+/// their subscriptions that matched, and the holds.
+fn group_claims(claims: Vec<Claim>) -> (Vec<Recipient>, Vec<(Hold, Flags)>) {
+    // one DM per user, no matter how many of their subscriptions matched
+    let mut users_to_notify: Vec<Recipient> = Vec::new();
+    let mut holds = Vec::new();
+    for claim in claims {
+        match claim {
+            Claim::Notify { uid, subscription } => {
+                match users_to_notify.iter_mut().find(|(u, _)| *u == uid) {
+                    Some((_, subscriptions)) => subscriptions.push(subscription),
+                    None => users_to_notify.push((uid, vec![subscription])),
+                }
+            }
+            Claim::Hold(hold, flags) => holds.push((hold, flags)),
+        }
+    }
+    (users_to_notify, holds)
+}
+
+/// Sends each subscription the release from its most preferred group,
+/// listing the others. Subscriptions that prefer the same release share a DM.
+async fn notify_held(
+    store: &StoreHandle,
+    messenger: &Arc<dyn Messenger>,
+    user: u64,
+    held: Held,
+    deferred: &mut DeferredQueue,
+) -> Result<()> {
+    for choice in choose(&held.releases, held.holds) {
+        let best = choice.release;
+        let mut user_store = store.write().await;
+        let subscriptions: Vec<String> = choice
+            .holds
+            .into_iter()
+            .filter(|hold| user_store.claim_held(hold, &best.entry.release))
+            .map(|hold| hold.subscription)
+            .collect();
+        drop(user_store);
+        if subscriptions.is_empty() || held.sent.contains(&best.entry.link) {
+            continue;
+        }
+        let alternatives = choice
+            .alternatives
+            .iter()
+            // a few are enough, see field_value for the embed limit
+            .take(5)
+            .map(|r| format!("[{}]({})", r.entry.title, r.entry.link))
+            .collect();
+        send(
+            store,
+            messenger,
+            best,
+            vec![(user, subscriptions)],
+            alternatives,
+            deferred,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Picks the best of the releases for each hold by its subscription's
+/// preference.
+fn choose(releases: &[Arc<Release>], holds: Vec<(Hold, Flags)>) -> Vec<Choice> {
+    let mut choices: Vec<Choice> = Vec::new();
+    for (hold, flags) in holds {
+        let mut ranked = releases.to_vec();
+        // stable sort, so equally ranked releases keep their arrival order
+        ranked.sort_by_key(|r| flags.rank(r.entry.release.group.as_deref()));
+        if ranked.is_empty() {
+            continue;
+        }
+        let best = ranked.remove(0);
+        match choices.iter_mut().find(|c| Arc::ptr_eq(&c.release, &best)) {
+            Some(choice) => choice.holds.push(hold),
+            None => choices.push(Choice {
+                release: best,
+                holds: vec![hold],
+                alternatives: ranked,
+            }),
+        }
+    }
+    choices
+}
+
+async fn send(
+    store: &StoreHandle,
+    messenger: &Arc<dyn Messenger>,
+    release: Arc<Release>,
+    users_to_notify: Vec<Recipient>,
+    alternatives: Vec<String>,
+    deferred: &mut DeferredQueue,
+) -> Result<()> {
+    if users_to_notify.is_empty() {
+        return Ok(());
+    }
+    let user_store = store.read().await;
+    let profiles: Vec<Profile> = users_to_notify
+        .iter()
+        .map(|(user, _)| user_store.profile(*user))
+        .collect();
+    drop(user_store);
+    let now = Utc::now();
+    let mut jset = JoinSet::new();
+    for ((user, subscriptions), profile) in users_to_notify.into_iter().zip(profiles) {
+        let notification = Notification {
+            release: Arc::clone(&release),
+            subscriptions,
+            alternatives: alternatives.clone(),
+        };
+        match profile.delivery_time(now) {
+            Some(at) => deferred.push(user, at, notification),
+            None => {
+                jset.spawn(deliver(
+                    Arc::clone(store),
+                    Arc::clone(messenger),
+                    user,
+                    profile,
+                    vec![notification],
+                ));
             }
+        }
+    }
+    // wait for all of them, dropping the set would abort the rest
+    while let Some(res) = jset.join_next().await {
+        match res {
+            Ok(Err(e)) => log::error!("error while dming user: {e}"),
+            Err(e) => log::error!("dm task failed: {e}"),
+            Ok(Ok(())) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Sends what piled up during the user's quiet hours or until their digest.
+async fn notify_deferred(
+    store: &StoreHandle,
+    messenger: &Arc<dyn Messenger>,
+    user: u64,
+    deferred: Deferred,
+) -> Result<()> {
+    // read now, the user may have changed their settings in the meantime
+    let profile = store.read().await.profile(user);
+    if let Err(e) = deliver(
+        Arc::clone(store),
+        Arc::clone(messenger),
+        user,
+        profile,
+        deferred.notifications,
+    )
+    .await
+    {
+        log::error!("error while dming user: {e}");
+    }
+    Ok(())
+}
+
+/// Sends the notifications and records them in the user's history.
+async fn deliver(
+    store: StoreHandle,
+    messenger: Arc<dyn Messenger>,
+    user: u64,
+    profile: Profile,
+    notifications: Vec<Notification>,
+) -> Result<()> {
+    let sent = notify_user(messenger.as_ref(), user, profile, &notifications).await;
+    let status = match &sent {
+        Ok(()) => Status::Sent,
+        Err(e) => Status::Failed(e.to_string()),
+    };
+    let sent_at = Utc::now().timestamp();
+    let mut user_store = store.write().await;
+    for n in notifications {
+        let record = Record {
+            uid: user,
+            subscriptions: n.subscriptions,
+            title: n.release.entry.title.clone(),
+            link: n.release.entry.link.clone(),
+            magnet: n.release.torrent.as_ref().ok().map(|t| t.magnet.clone()),
+            sent_at,
+            status: status.clone(),
         };
-        let http: Http = Http::new(&env::var("DISCORD_TOKEN")?);
-        let notify_data = Arc::new((magnet, http, entry));
-        let mut jset = JoinSet::new();
-        for user in users_to_notify.into_iter() {
-            jset.spawn(notify_user(user, Arc::clone(&notify_data)));
+        if let Err(e) = user_store.record(record) {
+            log::error!("could not record notification for {user}: {e}");
+        }
+    }
+    sent
+}
+
+/// DMs the notifications, several of them are bundled as a digest.
+async fn notify_user(
+    messenger: &dyn Messenger,
+    user: u64,
+    profile: Profile,
+    notifications: &[Notification],
+) -> Result<()> {
+    let labels = profile.labels();
+    let heading = match notifications.len() {
+        1 => String::new(),
+        n => format!("**{}** ({n})", labels.digest),
+    };
+    match profile.format {
+        Format::Embed => {
+            // discord allows at most 10 embeds with 6000 characters in
+            // total per message, the plain text is about as long
+            let mut batches: Vec<(usize, Vec<CreateEmbed>)> = vec![(0, Vec::new())];
+            for n in notifications {
+                let len = render_plain(n, &profile).chars().count();
+                let (total, embeds) = batches.last().unwrap();
+                if !embeds.is_empty() && (embeds.len() == 10 || total + len > 5500) {
+                    batches.push((0, Vec::new()));
+                }
+                let (total, embeds) = batches.last_mut().unwrap();
+                *total += len;
+                embeds.push(render_embed(n, &profile));
+            }
+            for (i, (_, embeds)) in batches.into_iter().enumerate() {
+                let content = if i == 0 { heading.as_str() } else { "" };
+                let msg = CreateMessage::new().content(content).embeds(embeds);
+                messenger.dm(user, msg).await?;
+            }
         }
-        while let Some(Err(e)) = jset.join_next().await {
-            log::error!("error while dming user: {e}");
+        Format::Plain => {
+            let mut messages = vec![heading];
+            for n in notifications {
+                let text = render_plain(n, &profile);
+                let last = messages.last_mut().unwrap();
+                // messages are limited to 2000 characters
+                if last.chars().count() + text.chars().count() + 2 > 2000 {
+                    messages.push(String::new());
+                }
+                let last = messages.last_mut().unwrap();
+                if !last.is_empty() {
+                    last.push_str("\n\n");
+                }
+                last.extend(text.chars().take(2000));
+            }
+            for content in messages.into_iter().filter(|m| !m.is_empty()) {
+                messenger
+                    .dm(user, CreateMessage::new().content(content))
+                    .await?;
+            }
         }
     }
     Ok(())
 }
 
-async fn notify_user(user: u64, data: Arc<(String, Http, RssEntry)>) -> Result<()> {
-    let user = UserId::from(user).to_user(&data.1).await?;
-    let embed = CreateEmbed::new()
-        .title(&data.2.title)
-        .description(&data.2.link)
-        .field(
-            "Download",
-            format!("[Use Magnet](https://callmemsl.github.io/makima?r={})", data.0),
-            true,
+/// Download link, size and release time in the user's language and timezone.
+fn details(n: &Notification, profile: &Profile) -> Vec<(&'static str, String)> {
+    let labels = profile.labels();
+    let mut details = Vec::new();
+    match &n.release.torrent {
+        Ok(t) => {
+            let link = profile.magnet_link(&t.magnet);
+            let download = if link.starts_with("http") {
+                format!("[{}]({link})", labels.use_magnet)
+            } else {
+                link
+            };
+            details.push((labels.download, download));
+            let files = if t.file_count == 1 {
+                labels.files.0
+            } else {
+                labels.files.1
+            };
+            let mut size = format_size(t.size);
+            if labels.decimal_comma {
+                size = size.replace('.', ",");
+            }
+            details.push((labels.size, format!("{size} ({} {files})", t.file_count)));
+        }
+        Err(e) => details.push((labels.download, e.to_string())),
+    }
+    let released = n.release.entry.pub_date.with_timezone(&profile.timezone());
+    details.push((
+        labels.released,
+        format!(
+            "{} {}",
+            released.format("%Y-%m-%d %H:%M"),
+            profile.get("timezone")
+        ),
+    ));
+    details.push((labels.feed, n.release.entry.feed.clone()));
+    details
+}
+
+fn render_embed(n: &Notification, profile: &Profile) -> CreateEmbed {
+    let labels = profile.labels();
+    let mut embed = CreateEmbed::new()
+        .title(&n.release.entry.title)
+        .description(&n.release.entry.link);
+    for (name, value) in details(n, profile) {
+        let value: String = value.chars().take(1024).collect();
+        embed = embed.field(name, value, true);
+    }
+    let matched: Vec<String> = n.subscriptions.iter().map(|s| format!("`{s}`")).collect();
+    embed = embed.field(labels.matched, field_value(&matched), false);
+    if !n.alternatives.is_empty() {
+        embed = embed.field(labels.alternatives, field_value(&n.alternatives), false);
+    }
+    embed
+}
+
+/// The lines joined for an embed field, which is limited to 1024
+/// characters. Lines that don't fit are left out rather than cut, so no
+/// link ends up half rendered, unless even the first one is too long.
+fn field_value(lines: &[String]) -> String {
+    let mut value = String::new();
+    let mut len = 0;
+    for line in lines {
+        let line_len = line.chars().count() + usize::from(!value.is_empty());
+        if len + line_len > 1024 {
+            break;
+        }
+        if !value.is_empty() {
+            value.push('\n');
+        }
+        value.push_str(line);
+        len += line_len;
+    }
+    if value.is_empty() {
+        value = lines
+            .first()
+            .map_or(String::new(), |l| l.chars().take(1024).collect());
+    }
+    value
+}
+
+fn render_plain(n: &Notification, profile: &Profile) -> String {
+    let labels = profile.labels();
+    let mut lines = vec![
+        format!("**{}**", n.release.entry.title),
+        format!("<{}>", n.release.entry.link),
+    ];
+    for (name, value) in details(n, profile) {
+        lines.push(format!("{name}: {value}"));
+    }
+    let matched: Vec<String> = n.subscriptions.iter().map(|s| format!("`{s}`")).collect();
+    lines.push(format!("{}: {}", labels.matched, matched.join(", ")));
+    if !n.alternatives.is_empty() {
+        lines.push(format!(
+            "{}: {}",
+            labels.alternatives,
+            n.alternatives.join(", ")
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::TempDir;
+    use crate::query::Query;
+    use crate::store::{Entry, UserStore};
+    use std::sync::Mutex;
+    use tokio::sync::RwLock;
+
+    /// Keeps the DMs as the JSON Discord would get.
+    #[derive(Default)]
+    struct Recorder {
+        sent: Mutex<Vec<(u64, String)>>,
+    }
+
+    #[async_trait]
+    impl Messenger for Recorder {
+        async fn dm(&self, user: u64, message: CreateMessage) -> Result<()> {
+            let json = serde_json::to_string(&message)?;
+            self.sent.lock().unwrap().push((user, json));
+            Ok(())
+        }
+    }
+
+    fn release(title: &str) -> Arc<Release> {
+        Arc::new(Release {
+            entry: RssEntry {
+                id: title.to_string(),
+                feed: "nyaa.si".into(),
+                title: title.to_string(),
+                link: format!("https://nyaa.si/{title}"),
+                pub_date: chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap(),
+                release: ReleaseInfo::parse(title),
+            },
+            torrent: Err(anyhow!("not fetched")),
+        })
+    }
+
+    fn hold(id: u64, prefer: &str) -> (Hold, Flags) {
+        let mut flags = Flags::default();
+        flags.set(&format!("prefer={prefer}")).unwrap();
+        let hold = Hold {
+            uid: 1,
+            id,
+            subscription: format!("Frieren {id}"),
+            show: "frieren".into(),
+            episode: 12,
+        };
+        (hold, flags)
+    }
+
+    #[test]
+    fn test_one_notification_per_user() {
+        let mut us = UserStore::in_memory();
+        for (uid, query) in [(1, "Frieren"), (2, "Frieren"), (1, "1080p")] {
+            let query = Query::parse(query).unwrap();
+            us.add(Entry::new(uid, query, Flags::default())).unwrap();
+        }
+        let r = release("[SubsPlease] Frieren - 12 (1080p)");
+        let claims = us.claim_matching("nyaa.si", &r.entry.title, &r.entry.release, None);
+        let (users, holds) = group_claims(claims);
+        assert!(holds.is_empty());
+        assert_eq!(
+            users,
+            vec![
+                (1, vec!["Frieren".to_string(), "1080p".to_string()]),
+                (2, vec!["Frieren".to_string()]),
+            ]
         );
-    let msg = CreateMessage::new().content("").embed(embed);
-    user.direct_message(&data.1, msg).await?;
-    Ok(())
+        let n = Notification {
+            release: r,
+            subscriptions: users[0].1.clone(),
+            alternatives: Vec::new(),
+        };
+        let text = render_plain(&n, &Profile::default());
+        assert!(text.contains("`Frieren`, `1080p`"));
+    }
+
+    #[tokio::test]
+    async fn test_send_and_defer() {
+        let mut us = UserStore::in_memory();
+        for uid in [1, 2] {
+            let query = Query::parse("Frieren").unwrap();
+            us.add(Entry::new(uid, query, Flags::default())).unwrap();
+        }
+        let mut digest = Profile::default();
+        digest.set("digest", "hourly").unwrap();
+        us.set_profile(2, digest).unwrap();
+        let store: StoreHandle = Arc::new(RwLock::new(us));
+        let recorder = Arc::new(Recorder::default());
+        let messenger: Arc<dyn Messenger> = recorder.clone();
+        let mut deferred = DeferredQueue::default();
+
+        let r = release("[SubsPlease] Frieren - 12 (1080p)");
+        let recipients = vec![(1, vec!["Frieren".into()]), (2, vec!["Frieren".into()])];
+        send(&store, &messenger, r, recipients, Vec::new(), &mut deferred)
+            .await
+            .unwrap();
+        {
+            let sent = recorder.sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, 1);
+            assert!(sent[0].1.contains("[SubsPlease] Frieren - 12 (1080p)"));
+        }
+        // the digest user gets it later, even after a restart
+        let dir = TempDir::new("deferred");
+        let path = dir.join(DEFERRED_FILE);
+        assert!(deferred.dirty);
+        deferred.save(&path).unwrap();
+        assert!(!deferred.dirty);
+        let mut deferred = DeferredQueue::open(&path).unwrap();
+        assert!(deferred.take_due(Instant::now()).is_empty());
+        assert!(!deferred.dirty);
+        let d = deferred.users.remove(&2).unwrap();
+        assert!(deferred.users.is_empty());
+        assert!(d.deadline > Instant::now());
+        assert_eq!(
+            d.notifications[0].subscriptions,
+            vec!["Frieren".to_string()]
+        );
+        assert_eq!(d.notifications[0].release.entry.release.episode, Some(12));
+        match &d.notifications[0].release.torrent {
+            Err(e) => assert_eq!(e.to_string(), "not fetched"),
+            Ok(_) => panic!("the fetch error was lost"),
+        }
+        notify_deferred(&store, &messenger, 2, d).await.unwrap();
+        assert_eq!(recorder.sent.lock().unwrap()[1].0, 2);
+        let us = store.read().await;
+        assert_eq!(us.history(1, 5).len(), 1);
+        assert_eq!(us.history(2, 5)[0].status, Status::Sent);
+    }
+
+    #[test]
+    fn test_unreadable_deferred() {
+        let dir = TempDir::new("unreadable");
+        let path = dir.join(DEFERRED_FILE);
+        std::fs::write(&path, b"[{\"user\":").unwrap();
+        let mut deferred = DeferredQueue::open(&path).unwrap();
+        assert!(deferred.users.is_empty());
+        deferred.save(&path).unwrap();
+        let aside = std::fs::read(dir.join("deferred.json.unreadable")).unwrap();
+        assert_eq!(aside, b"[{\"user\":");
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_all() {
+        /// Answers later the higher the user id.
+        struct Slow(Recorder);
+
+        #[async_trait]
+        impl Messenger for Slow {
+            async fn dm(&self, user: u64, message: CreateMessage) -> Result<()> {
+                tokio::time::sleep(Duration::from_millis(10 * user)).await;
+                self.0.dm(user, message).await
+            }
+        }
+
+        let mut us = UserStore::in_memory();
+        for uid in [1, 2, 3] {
+            let query = Query::parse("Frieren").unwrap();
+            us.add(Entry::new(uid, query, Flags::default())).unwrap();
+        }
+        let store: StoreHandle = Arc::new(RwLock::new(us));
+        let slow = Arc::new(Slow(Recorder::default()));
+        let messenger: Arc<dyn Messenger> = slow.clone();
+        let r = release("[SubsPlease] Frieren - 12 (1080p)");
+        let recipients = [1, 2, 3]
+            .into_iter()
+            .map(|uid| (uid, vec!["Frieren".to_string()]))
+            .collect();
+        send(
+            &store,
+            &messenger,
+            r,
+            recipients,
+            Vec::new(),
+            &mut DeferredQueue::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(slow.0.sent.lock().unwrap().len(), 3);
+        let us = store.read().await;
+        assert!([1, 2, 3].iter().all(|uid| us.history(*uid, 5).len() == 1));
+    }
+
+    #[test]
+    fn test_choose_per_subscription() {
+        let releases = vec![
+            release("[SubsPlease] Frieren - 12 (1080p)"),
+            release("[ASW] Frieren - 12 (1080p)"),
+            release("[Erai-raws] Frieren - 12 (1080p)"),
+        ];
+        let holds = vec![
+            hold(1, "ASW,SubsPlease"),
+            hold(2, "Erai-raws"),
+            hold(3, "ASW"),
+        ];
+        let choices = choose(&releases, holds);
+        assert_eq!(choices.len(), 2);
+        assert!(Arc::ptr_eq(&choices[0].release, &releases[1]));
+        let ids: Vec<u64> = choices[0].holds.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        // ranked by the preference of subscription 1
+        assert!(Arc::ptr_eq(&choices[0].alternatives[0], &releases[0]));
+        assert!(Arc::ptr_eq(&choices[1].release, &releases[2]));
+        assert_eq!(choices[1].holds[0].id, 2);
+    }
+
+    #[test]
+    fn test_field_value() {
+        let link = |i: usize| format!("[{}]({})", "x".repeat(300), i);
+        let lines: Vec<String> = (0..5).map(link).collect();
+        let value = field_value(&lines);
+        assert!(value.chars().count() <= 1024);
+        assert_eq!(value.lines().count(), 3);
+        assert!(value.ends_with("(2)"));
+        assert_eq!(field_value(&["y".repeat(2000)]).chars().count(), 1024);
+        assert_eq!(field_value(&[]), "");
+    }
 }
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p: OsString = path.as_os_str().to_owned();
+    p.push(suffix);
+    p.into()
+}
+
+/// Where the previous generation of `path` is kept.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Replaces `path` with `data` without ever leaving a half written file.
+///
+/// The data goes to a temp file that is synced and then renamed over
+/// `path`, the old file stays around as `<path>.bak`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    if path.exists() {
+        std::fs::rename(path, backup_path(path))?;
+    }
+    std::fs::rename(&tmp, path)?;
+    // the renames only survive a crash once the directory is synced
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Whether `path` or its previous generation exists.
+pub fn exists(path: &Path) -> bool {
+    path.exists() || backup_path(path).exists()
+}
+
+/// Reads and parses `path`, falling back to the previous generation if the
+/// file is missing or doesn't parse. A recovered backup is written back as
+/// the primary file.
+pub fn read_recover<T>(path: &Path, parse: impl Fn(&[u8]) -> Result<T>) -> Result<T> {
+    let err = match std::fs::read(path)
+        .map_err(Into::into)
+        .and_then(|d| parse(&d))
+    {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    let Ok(data) = std::fs::read(&backup) else {
+        return Err(err);
+    };
+    let Ok(v) = parse(&data) else {
+        return Err(err);
+    };
+    log::warn!(
+        "{} is unreadable ({err}), recovered it from {}",
+        path.display(),
+        backup.display()
+    );
+    let tmp = with_suffix(path, ".tmp");
+    std::fs::write(&tmp, &data)?;
+    File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(v)
+}
+
+/// A directory for the files of a test, removed with everything in it
+/// when dropped, also when the test fails.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("makima-{name}-{}", std::process::id()));
+        // left over from a run that was killed
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn parse(data: &[u8]) -> Result<String> {
+        let s = String::from_utf8(data.to_vec())?;
+        s.starts_with("gen").then_some(s).ok_or(anyhow!("corrupt"))
+    }
+
+    #[test]
+    fn test_recover_previous_generation() {
+        let dir = TempDir::new("persist");
+        let path = dir.join("last.txt");
+        write_atomic(&path, b"gen1").unwrap();
+        write_atomic(&path, b"gen2").unwrap();
+        assert_eq!(read_recover(&path, parse).unwrap(), "gen2");
+
+        std::fs::write(&path, b"\0\0").unwrap();
+        assert_eq!(read_recover(&path, parse).unwrap(), "gen1");
+        assert_eq!(std::fs::read(&path).unwrap(), b"gen1");
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_recover(&path, parse).unwrap(), "gen1");
+
+        std::fs::write(backup_path(&path), b"").unwrap();
+        std::fs::write(&path, b"").unwrap();
+        assert!(read_recover(&path, parse).is_err());
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use url::form_urlencoded;
+
+const DEFAULT_REDIRECT: &str = "https://callmemsl.github.io/makima?r=";
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default, Debug)]
+pub enum Format {
+    #[default]
+    Embed,
+    /// Plain text, for clients that hide embeds.
+    Plain,
+}
+
+/// When notifications are bundled into one DM instead of sent one by one.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default, Debug)]
+pub enum Digest {
+    #[default]
+    Off,
+    /// At the start of every hour.
+    Hourly,
+    /// Once a day at this many minutes after local midnight.
+    Daily(u32),
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default, Debug)]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Default, Debug)]
+pub enum Redirect {
+    /// The makima page that opens the magnet.
+    #[default]
+    Default,
+    /// The bare magnet link.
+    Off,
+    /// A prefix the magnet gets appended to.
+    Url(String),
+}
+
+/// Labels of a DM in the user's language.
+pub struct Labels {
+    pub download: &'static str,
+    pub use_magnet: &'static str,
+    pub size: &'static str,
+    pub files: (&'static str, &'static str),
+    pub released: &'static str,
+    pub feed: &'static str,
+    pub matched: &'static str,
+    pub alternatives: &'static str,
+    pub digest: &'static str,
+    pub decimal_comma: bool,
+}
+
+const EN: Labels = Labels {
+    download: "Download",
+    use_magnet: "Use Magnet",
+    size: "Size",
+    files: ("file", "files"),
+    released: "Released",
+    feed: "Feed",
+    matched: "Matched",
+    alternatives: "Alternatives",
+    digest: "New releases",
+    decimal_comma: false,
+};
+
+const DE: Labels = Labels {
+    download: "Download",
+    use_magnet: "Magnet öffnen",
+    size: "Größe",
+    files: ("Datei", "Dateien"),
+    released: "Veröffentlicht",
+    feed: "Feed",
+    matched: "Treffer",
+    alternatives: "Alternativen",
+    digest: "Neue Releases",
+    decimal_comma: true,
+};
+
+/// Per user preferences for how and when DMs are sent, changed with the
+/// `settings` command.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Default, Debug)]
+pub struct Profile {
+    /// Offset from UTC in seconds, times are shown and scheduled in it.
+    utc_offset: i32,
+    pub format: Format,
+    pub digest: Digest,
+    /// Local start and end hour, DMs in between wait until the end.
+    quiet: Option<(u32, u32)>,
+    pub locale: Locale,
+    pub redirect: Redirect,
+}
+
+impl Profile {
+    pub const KEYS: [&'static str; 6] = [
+        "timezone", "format", "digest", "quiet", "locale", "redirect",
+    ];
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "timezone" => self.utc_offset = parse_offset(value)?,
+            "format" => {
+                self.format = match value {
+                    "embed" => Format::Embed,
+                    "plain" => Format::Plain,
+                    _ => bail!("use format=embed or format=plain"),
+                }
+            }
+            "digest" => {
+                self.digest = match value {
+                    "off" => Digest::Off,
+                    "hourly" => Digest::Hourly,
+                    time => {
+                        let t = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+                            anyhow!("use digest=off, digest=hourly or digest=18:00")
+                        })?;
+                        Digest::Daily(t.hour() * 60 + t.minute())
+                    }
+                }
+            }
+            "quiet" => {
+                self.quiet = match value {
+                    "off" => None,
+                    range => {
+                        let hours = range.split_once('-').and_then(|(a, b)| {
+                            Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+                        });
+                        match hours {
+                            Some((start, end)) if start < 24 && end < 24 && start != end => {
+                                Some((start, end))
+                            }
+                            _ => bail!("use quiet=off or quiet=22-7"),
+                        }
+                    }
+                }
+            }
+            "locale" => {
+                self.locale = match value {
+                    "en" => Locale::En,
+                    "de" => Locale::De,
+                    _ => bail!("supported locales are en and de"),
+                }
+            }
+            "redirect" => {
+                self.redirect = match value {
+                    "default" => Redirect::Default,
+                    "off" => Redirect::Off,
+                    url if url.starts_with("https://") || url.starts_with("http://") => {
+                        Redirect::Url(url.to_string())
+                    }
+                    _ => bail!("use redirect=default, redirect=off or a http(s) url"),
+                }
+            }
+            _ => bail!(
+                "unknown setting `{key}`, available are {}",
+                Self::KEYS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    /// The value of a setting in the form [`Profile::set`] accepts.
+    pub fn get(&self, key: &str) -> String {
+        match key {
+            "timezone" => format_offset(self.utc_offset),
+            "format" => match self.format {
+                Format::Embed => "embed".into(),
+                Format::Plain => "plain".into(),
+            },
+            "digest" => match self.digest {
+                Digest::Off => "off".into(),
+                Digest::Hourly => "hourly".into(),
+                Digest::Daily(m) => format!("{:02}:{:02}", m / 60, m % 60),
+            },
+            "quiet" => match self.quiet {
+                None => "off".into(),
+                Some((start, end)) => format!("{start}-{end}"),
+            },
+            "locale" => match self.locale {
+                Locale::En => "en".into(),
+                Locale::De => "de".into(),
+            },
+            "redirect" => match &self.redirect {
+                Redirect::Default => "default".into(),
+                Redirect::Off => "off".into(),
+                Redirect::Url(url) => url.clone(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    pub fn timezone(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.utc_offset).unwrap_or(FixedOffset::east_opt(0).unwrap())
+    }
+
+    pub fn labels(&self) -> &'static Labels {
+        match self.locale {
+            Locale::En => &EN,
+            Locale::De => &DE,
+        }
+    }
+
+    /// Where the download link of a DM points to, the magnet is encoded
+    /// when it is passed on to a page.
+    pub fn magnet_link(&self, magnet: &str) -> String {
+        // history from older versions has the links encoded already
+        let magnet = match magnet.starts_with("magnet%3A") {
+            true => form_urlencoded::parse(magnet.as_bytes())
+                .next()
+                .map_or(magnet.to_string(), |(link, _)| link.into_owned()),
+            false => magnet.to_string(),
+        };
+        let encoded = || form_urlencoded::byte_serialize(magnet.as_bytes()).collect::<String>();
+        match &self.redirect {
+            Redirect::Default => format!("{DEFAULT_REDIRECT}{}", encoded()),
+            Redirect::Off => magnet,
+            Redirect::Url(prefix) => format!("{prefix}{}", encoded()),
+        }
+    }
+
+    /// When a notification created at `now` should be sent, `None` if
+    /// right away.
+    pub fn delivery_time(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut at = match self.digest {
+            Digest::Off => now,
+            Digest::Hourly => {
+                let hour = now.with_timezone(&self.timezone()).hour();
+                self.next_local(now, (hour + 1) % 24 * 60)
+            }
+            Digest::Daily(minutes) => self.next_local(now, minutes),
+        };
+        if let Some((start, end)) = self.quiet {
+            let hour = at.with_timezone(&self.timezone()).hour();
+            let quiet = if start < end {
+                hour >= start && hour < end
+            } else {
+                hour >= start || hour < end
+            };
+            if quiet {
+                at = self.next_local(at, end * 60);
+            }
+        }
+        (at > now).then_some(at)
+    }
+
+    /// The first time after `after` that is `minutes` past local midnight.
+    fn next_local(&self, after: DateTime<Utc>, minutes: u32) -> DateTime<Utc> {
+        let tz = self.timezone();
+        let local = after.with_timezone(&tz);
+        let time = NaiveTime::from_hms_opt(minutes / 60, minutes % 60, 0).unwrap();
+        let mut at = tz
+            .from_local_datetime(&local.date_naive().and_time(time))
+            .unwrap()
+            .with_timezone(&Utc);
+        if at <= after {
+            at += Duration::days(1);
+        }
+        at
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let settings: Vec<String> = Self::KEYS
+            .iter()
+            .map(|k| format!("{k}={}", self.get(k)))
+            .collect();
+        write!(f, "{}", settings.join("\n"))
+    }
+}
+
+/// Parses `UTC`, `UTC+2`, `+05:30`, `-8` and the like into seconds.
+fn parse_offset(s: &str) -> Result<i32> {
+    let err = || anyhow!("use timezone=UTC, timezone=UTC+2, timezone=-05:30, ...");
+    let upper = s.trim().to_uppercase();
+    let rest = upper
+        .strip_prefix("UTC")
+        .or(upper.strip_prefix("GMT"))
+        .unwrap_or(&upper);
+    if rest.is_empty() {
+        return Ok(0);
+    }
+    let (sign, rest) = match rest.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return Err(err()),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i32 = hours.parse().map_err(|_| err())?;
+    let minutes: i32 = minutes.parse().map_err(|_| err())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(err());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+fn format_offset(seconds: i32) -> String {
+    if seconds == 0 {
+        return "UTC".into();
+    }
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    format!("UTC{sign}{:02}:{:02}", seconds / 3600, seconds % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let mut p = Profile::default();
+        p.set("timezone", "UTC+5:30").unwrap();
+        p.set("digest", "18:00").unwrap();
+        p.set("quiet", "22-7").unwrap();
+        assert_eq!(p.get("timezone"), "UTC+05:30");
+        assert_eq!(p.get("digest"), "18:00");
+        assert_eq!(p.get("quiet"), "22-7");
+        assert!(p.set("timezone", "Europe/Berlin").is_err());
+        assert!(p.set("quiet", "7-7").is_err());
+        assert!(p.set("redirect", "javascript:alert(1)").is_err());
+
+        let mut copy = Profile::default();
+        for k in Profile::KEYS {
+            copy.set(k, &p.get(k)).unwrap();
+        }
+        assert_eq!(copy, p);
+    }
+
+    #[test]
+    fn test_magnet_link() {
+        let data = b"d4:infod6:lengthi100e4:name9:Frieren 112:piece lengthi16384e6:pieces0:ee";
+        let magnet = crate::torrent::Torrent::from_bytes(data)
+            .unwrap()
+            .create_magnet_link()
+            .unwrap();
+        assert!(magnet.starts_with("magnet:?xt=urn:btih:"));
+        assert!(magnet.ends_with("&dn=Frieren+1"));
+
+        let mut p = Profile::default();
+        p.set("redirect", "off").unwrap();
+        assert_eq!(p.magnet_link(&magnet), magnet);
+        let encoded: String = form_urlencoded::byte_serialize(magnet.as_bytes()).collect();
+        assert_eq!(p.magnet_link(&encoded), magnet);
+        p.set("redirect", "default").unwrap();
+        let link = p.magnet_link(&magnet);
+        assert_eq!(link, format!("{DEFAULT_REDIRECT}{encoded}"));
+        assert!(link.starts_with("https://callmemsl.github.io/makima?r=magnet%3A%3Fxt%3D"));
+    }
+
+    #[test]
+    fn test_delivery_time() {
+        let mut p = Profile::default();
+        p.set("timezone", "UTC+2").unwrap();
+        assert_eq!(p.delivery_time(utc("2024-05-01T12:00:00Z")), None);
+
+        // 01:30 local is quiet until 07:00 local
+        p.set("quiet", "22-7").unwrap();
+        assert_eq!(
+            p.delivery_time(utc("2024-04-30T23:30:00Z")),
+            Some(utc("2024-05-01T05:00:00Z"))
+        );
+
+        p.set("quiet", "off").unwrap();
+        p.set("digest", "hourly").unwrap();
+        assert_eq!(
+            p.delivery_time(utc("2024-05-01T12:20:00Z")),
+            Some(utc("2024-05-01T13:00:00Z"))
+        );
+
+        p.set("digest", "18:00").unwrap();
+        assert_eq!(
+            p.delivery_time(utc("2024-05-01T17:00:00Z")),
+            Some(utc("2024-05-02T16:00:00Z"))
+        );
+    }
+}
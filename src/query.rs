@@ -0,0 +1,452 @@
+use crate::release::FieldFilter;
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+const REGEX_PREFIX: &str = "re:";
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum Pattern {
+    Substring(String),
+    Regex(String),
+    Field(FieldFilter),
+}
+
+impl Pattern {
+    pub fn regex(re: &str) -> Result<Self> {
+        Regex::new(re).map_err(|e| anyhow!("invalid regex `{re}`: {e}"))?;
+        Ok(Pattern::Regex(re.to_string()))
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Substring(s) if is_bare(s) => write!(f, "{s}"),
+            Pattern::Substring(s) => write!(f, "\"{}\"", s.replace('"', "\\\"")),
+            Pattern::Regex(re) => write!(f, "{REGEX_PREFIX}\"{}\"", re.replace('"', "\\\"")),
+            Pattern::Field(filter) => write!(f, "{filter}"),
+        }
+    }
+}
+
+/// A subscription filter.
+///
+/// Grammar, loosest binding first:
+/// ```text
+/// or    := and ("OR" and)*
+/// and   := unary (("AND" | ";")? unary)*
+/// unary := ("NOT" | "-") unary | atom
+/// atom  := "(" or ")" | "\"phrase\"" | re:"regex" | re:word | key:value | word+
+/// ```
+/// `key:value` filters on a field parsed from the release name, see
+/// [`FieldFilter`] for the available keys.
+/// Consecutive plain words form a single phrase, so `One Piece` still means
+/// the substring `One Piece` like it did before the query language existed.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum Query {
+    Term(Pattern),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let query = parser.or()?;
+        if let Some(t) = parser.tokens.get(parser.pos) {
+            bail!("unexpected {t}");
+        }
+        if !query.has_positive() {
+            bail!("a query needs at least one term that is not negated");
+        }
+        Ok(query)
+    }
+
+    pub fn matches(&self, is_match: &impl Fn(&Pattern) -> bool) -> bool {
+        match self {
+            Query::Term(p) => is_match(p),
+            Query::Not(q) => !q.matches(is_match),
+            Query::And(qs) => qs.iter().all(|q| q.matches(is_match)),
+            Query::Or(qs) => qs.iter().any(|q| q.matches(is_match)),
+        }
+    }
+
+    pub fn patterns(&self) -> Vec<&Pattern> {
+        let mut out = Vec::new();
+        self.collect_patterns(&mut out);
+        out
+    }
+
+    fn collect_patterns<'a>(&'a self, out: &mut Vec<&'a Pattern>) {
+        match self {
+            Query::Term(p) => out.push(p),
+            Query::Not(q) => q.collect_patterns(out),
+            Query::And(qs) | Query::Or(qs) => qs.iter().for_each(|q| q.collect_patterns(out)),
+        }
+    }
+
+    /// Whether a title has to contain something for this query to match.
+    /// Queries made only of negations would match nearly every release.
+    fn has_positive(&self) -> bool {
+        match self {
+            Query::Term(_) => true,
+            Query::Not(_) => false,
+            Query::And(qs) => qs.iter().any(Query::has_positive),
+            Query::Or(qs) => qs.iter().all(Query::has_positive),
+        }
+    }
+
+    fn fmt_nested(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::And(_) | Query::Or(_) => write!(f, "({self})"),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::Term(p) => write!(f, "{p}"),
+            Query::Not(q) => match q.as_ref() {
+                Query::Term(p) => write!(f, "-{p}"),
+                q => {
+                    write!(f, "NOT ")?;
+                    q.fmt_nested(f)
+                }
+            },
+            Query::And(qs) => {
+                for (i, q) in qs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " AND ")?;
+                    }
+                    match q {
+                        Query::Or(_) => q.fmt_nested(f)?,
+                        q => write!(f, "{q}")?,
+                    }
+                }
+                Ok(())
+            }
+            Query::Or(qs) => {
+                for (i, q) in qs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " OR ")?;
+                    }
+                    write!(f, "{q}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Regex(String),
+    Field(&'static str, String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "`{w}`"),
+            Token::Quoted(q) => write!(f, "\"{q}\""),
+            Token::Regex(re) => write!(f, "{REGEX_PREFIX}{re}"),
+            Token::Field(key, value) => write!(f, "`{key}:{value}`"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Open => write!(f, "`(`"),
+            Token::Close => write!(f, "`)`"),
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';')
+}
+
+/// Whether a phrase prints back to the same phrase without quotes.
+fn is_bare(s: &str) -> bool {
+    !s.split(' ').any(|w| {
+        w.is_empty()
+            || matches!(w, "AND" | "OR" | "NOT")
+            || (w.starts_with('-') && w.len() > 1)
+            || w.starts_with(REGEX_PREFIX)
+            || field_key(w).is_some()
+            || w.chars().any(is_special)
+    })
+}
+
+/// The field key if `s` starts with `key:` for a known key.
+fn field_key(s: &str) -> Option<&'static str> {
+    FieldFilter::KEYS.into_iter().find(|key| {
+        s.strip_prefix(key)
+            .is_some_and(|rest| rest.starts_with(':'))
+    })
+}
+
+/// Reads a quoted string if the next char is a quote, a single word otherwise.
+fn read_value(chars: &mut Peekable<CharIndices>) -> Result<String> {
+    if chars.peek().is_some_and(|(_, c)| *c == '"') {
+        chars.next();
+        read_quoted(chars)
+    } else {
+        Ok(read_word(chars))
+    }
+}
+
+fn read_quoted(chars: &mut Peekable<CharIndices>) -> Result<String> {
+    let mut out = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '\\')) if chars.peek().is_some_and(|(_, c)| *c == '"') => {
+                chars.next();
+                out.push('"');
+            }
+            Some((_, '"')) => return Ok(out),
+            Some((_, c)) => out.push(c),
+            None => bail!("unterminated quote"),
+        }
+    }
+}
+
+fn read_word(chars: &mut Peekable<CharIndices>) -> String {
+    let mut out = String::new();
+    while let Some((_, c)) = chars.peek() {
+        if is_special(*c) {
+            break;
+        }
+        out.push(*c);
+        chars.next();
+    }
+    out
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            ';' => {
+                chars.next();
+                tokens.push(Token::And);
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Quoted(read_quoted(&mut chars)?));
+            }
+            // `-720p` negates, a lone `-` as in `Frieren - 12` is a word
+            '-' if s[i + 1..].starts_with(|c: char| !is_special(c) || c == '"' || c == '(') => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            _ if s[i..].starts_with(REGEX_PREFIX) => {
+                for _ in 0..REGEX_PREFIX.len() {
+                    chars.next();
+                }
+                tokens.push(Token::Regex(read_value(&mut chars)?));
+            }
+            _ if field_key(&s[i..]).is_some() => {
+                let key = field_key(&s[i..]).unwrap();
+                for _ in 0..=key.len() {
+                    chars.next();
+                }
+                tokens.push(Token::Field(key, read_value(&mut chars)?));
+            }
+            _ => {
+                let word = read_word(&mut chars);
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut qs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            qs.push(self.and()?);
+        }
+        Ok(flatten(qs, true))
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut qs = vec![self.unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.pos += 1;
+                    qs.push(self.unary()?);
+                }
+                None | Some(Token::Or) | Some(Token::Close) => break,
+                Some(_) => qs.push(self.unary()?),
+            }
+        }
+        Ok(flatten(qs, false))
+    }
+
+    fn unary(&mut self) -> Result<Query> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Query> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or(anyhow!("unexpected end of query"))?;
+        self.pos += 1;
+        match token {
+            Token::Open => {
+                let q = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    bail!("missing `)`");
+                }
+                self.pos += 1;
+                Ok(q)
+            }
+            Token::Quoted(s) => Ok(Query::Term(Pattern::Substring(s.clone()))),
+            Token::Regex(re) => Ok(Query::Term(Pattern::regex(re)?)),
+            Token::Field(key, value) => {
+                Ok(Query::Term(Pattern::Field(FieldFilter::parse(key, value)?)))
+            }
+            Token::Word(w) => {
+                let mut phrase = w.clone();
+                while let Some(Token::Word(w)) = self.peek() {
+                    phrase.push(' ');
+                    phrase.push_str(w);
+                    self.pos += 1;
+                }
+                Ok(Query::Term(Pattern::Substring(phrase)))
+            }
+            t => bail!("unexpected {t}"),
+        }
+    }
+}
+
+/// Merges nested operators of the same kind, `a AND (b AND c)` is `a AND b AND c`.
+fn flatten(qs: Vec<Query>, or: bool) -> Query {
+    if qs.len() == 1 {
+        return qs.into_iter().next().unwrap();
+    }
+    let mut out = Vec::with_capacity(qs.len());
+    for q in qs {
+        match q {
+            Query::Or(inner) if or => out.extend(inner),
+            Query::And(inner) if !or => out.extend(inner),
+            q => out.push(q),
+        }
+    }
+    if or {
+        Query::Or(out)
+    } else {
+        Query::And(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(s: &str) -> Query {
+        Query::Term(Pattern::Substring(s.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Query::parse("One Piece").unwrap(), term("One Piece"));
+        assert_eq!(
+            Query::parse("Frieren;-720p").unwrap(),
+            Query::And(vec![term("Frieren"), Query::Not(Box::new(term("720p")))])
+        );
+        assert_eq!(Query::parse("Frieren - 12").unwrap(), term("Frieren - 12"));
+        assert_eq!(
+            Query::parse("(SubsPlease OR Erai-raws) AND 1080p").unwrap(),
+            Query::And(vec![
+                Query::Or(vec![term("SubsPlease"), term("Erai-raws")]),
+                term("1080p")
+            ])
+        );
+        assert_eq!(
+            Query::parse(r#"re:"\d+ \(1080p\)" "Dr. Stone""#).unwrap(),
+            Query::And(vec![
+                Query::Term(Pattern::Regex(r"\d+ \(1080p\)".to_string())),
+                term("Dr. Stone")
+            ])
+        );
+        assert_eq!(
+            Query::parse(r#"group:SubsPlease res:1080 show:"Frieren""#).unwrap(),
+            Query::And(vec![
+                Query::Term(Pattern::Field(
+                    FieldFilter::parse("group", "SubsPlease").unwrap()
+                )),
+                Query::Term(Pattern::Field(FieldFilter::parse("res", "1080").unwrap())),
+                Query::Term(Pattern::Field(
+                    FieldFilter::parse("show", "Frieren").unwrap()
+                )),
+            ])
+        );
+        assert!(Query::parse("(a OR b").is_err());
+        assert!(Query::parse("a OR").is_err());
+        assert!(Query::parse("-720p").is_err());
+        assert!(Query::parse("re:(").is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for q in [
+            "SubsPlease OR Erai-raws AND 1080p",
+            "(SubsPlease OR Erai-raws) AND 1080p AND -Batch",
+            "NOT (a OR b) AND \"x;y\" AND re:\"\\d+\"",
+            "Frieren - 12",
+            "show:\"Sousou no Frieren\" AND ep:>12 AND \"ep:1\"",
+        ] {
+            let parsed = Query::parse(q).unwrap();
+            assert_eq!(Query::parse(&parsed.to_string()).unwrap(), parsed);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use crate::normalize::normalize;
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// Everything we can read out of a release name like
+/// `[Group] Show Name - 12v2 (1080p) [HEVC][ABCD1234].mkv`
+/// or `Show.Name.S01E12.1080p.WEB.x264-GROUP`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReleaseInfo {
+    pub group: Option<String>,
+    pub show: Option<String>,
+    /// Only known for scene style names, `S02E01` is season 2.
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    pub version: Option<u32>,
+    pub resolution: Option<u32>,
+    pub codec: Option<String>,
+    pub crc: Option<String>,
+    pub repack: bool,
+}
+
+struct Patterns {
+    extension: Regex,
+    leading_group: Regex,
+    bracketed: Regex,
+    resolution: Regex,
+    crc: Regex,
+    anime_episode: Regex,
+    scene_episode: Regex,
+    scene_group: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        extension: Regex::new(r"(?i)\.(mkv|mp4|avi|webm|ts)$").unwrap(),
+        leading_group: Regex::new(r"^\s*\[([^\]]+)\]").unwrap(),
+        bracketed: Regex::new(r"\[[^\]]*\]|\([^)]*\)").unwrap(),
+        resolution: Regex::new(r"(?i)\b(?:\d{3,4}x)?(\d{3,4})[pi]?\b").unwrap(),
+        crc: Regex::new(r"^[0-9A-Fa-f]{8}$").unwrap(),
+        anime_episode: Regex::new(r"^(.*?)\s+-\s+(\d{1,4})(?:v(\d+))?(?:\s|$)").unwrap(),
+        scene_episode: Regex::new(r"(?i)^(.*?)[\s.]S(\d{1,2})E(\d{1,4})(?:v(\d+))?\b").unwrap(),
+        scene_group: Regex::new(r"-([A-Za-z0-9]+)$").unwrap(),
+    })
+}
+
+/// Maps the many spellings of a codec to one name, `x265` and `H.265` are `hevc`.
+fn canonical_codec(s: &str) -> Option<&'static str> {
+    match s.to_lowercase().replace(['.', '-', ' '], "").as_str() {
+        "hevc" | "x265" | "h265" => Some("hevc"),
+        "avc" | "x264" | "h264" => Some("avc"),
+        "av1" => Some("av1"),
+        "vp9" => Some("vp9"),
+        _ => None,
+    }
+}
+
+impl ReleaseInfo {
+    pub fn parse(title: &str) -> Self {
+        let p = patterns();
+        let mut info = ReleaseInfo::default();
+        let title = p.extension.replace(title.trim(), "");
+
+        let mut rest = title.as_ref();
+        if let Some(c) = p.leading_group.captures(rest) {
+            info.group = Some(c[1].trim().to_string());
+            rest = &rest[c[0].len()..];
+        }
+
+        // tags in brackets, e.g. (1080p) [HEVC] [ABCD1234]
+        for tag in p.bracketed.find_iter(rest) {
+            let inner = &tag.as_str()[1..tag.as_str().len() - 1];
+            for word in inner.split([' ', ',', '_']) {
+                info.read_tag(word);
+            }
+        }
+        let name = p.bracketed.replace_all(rest, " ");
+        let name = name.trim();
+
+        // unbracketed tags, mostly scene style names
+        for word in name.split([' ', '.', '_', '-']) {
+            info.read_tag(word);
+        }
+
+        if let Some(c) = p.anime_episode.captures(name) {
+            info.show = Some(c[1].trim().to_string());
+            info.episode = c[2].parse().ok();
+            info.version = c.get(3).and_then(|v| v.as_str().parse().ok());
+        } else if let Some(c) = p.scene_episode.captures(name) {
+            info.show = Some(c[1].replace('.', " ").trim().to_string());
+            info.season = c[2].parse().ok();
+            info.episode = c[3].parse().ok();
+            info.version = c.get(4).and_then(|v| v.as_str().parse().ok());
+            if info.group.is_none() {
+                info.group = p.scene_group.captures(name).map(|c| c[1].to_string());
+            }
+        } else if !name.is_empty() {
+            info.show = Some(name.to_string());
+        }
+        info
+    }
+
+    fn read_tag(&mut self, word: &str) {
+        let p = patterns();
+        if word.eq_ignore_ascii_case("repack") || word.eq_ignore_ascii_case("proper") {
+            self.repack = true;
+        } else if let Some(codec) = canonical_codec(word) {
+            self.codec.get_or_insert(codec.to_string());
+        } else if p.crc.is_match(word) && word.chars().any(|c| c.is_ascii_alphabetic()) {
+            self.crc.get_or_insert(word.to_uppercase());
+        } else if let Some(c) = p.resolution.captures(word) {
+            let word_lower = word.to_lowercase();
+            if word_lower.ends_with('p') || word_lower.ends_with('i') || word.contains('x') {
+                self.resolution = self.resolution.or(c[1].parse().ok());
+            }
+        }
+    }
+}
+
+/// An inclusive range for numeric fields, written as `12`, `>12`, `<12`,
+/// `>=12`, `<=12` or `10-20`.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct NumRange {
+    min: Option<u32>,
+    max: Option<u32>,
+}
+
+impl NumRange {
+    fn parse(s: &str) -> Result<Self> {
+        let num = |s: &str| -> Result<u32> {
+            s.trim_end_matches(['p', 'P'])
+                .parse()
+                .map_err(|_| anyhow!("`{s}` is not a number"))
+        };
+        let range = if let Some(n) = s.strip_prefix(">=") {
+            NumRange {
+                min: Some(num(n)?),
+                max: None,
+            }
+        } else if let Some(n) = s.strip_prefix("<=") {
+            NumRange {
+                min: None,
+                max: Some(num(n)?),
+            }
+        } else if let Some(n) = s.strip_prefix('>') {
+            NumRange {
+                min: Some(
+                    num(n)?
+                        .checked_add(1)
+                        .ok_or(anyhow!("nothing is greater than `{n}`"))?,
+                ),
+                max: None,
+            }
+        } else if let Some(n) = s.strip_prefix('<') {
+            NumRange {
+                min: None,
+                max: Some(
+                    num(n)?
+                        .checked_sub(1)
+                        .ok_or(anyhow!("nothing is less than `{n}`"))?,
+                ),
+            }
+        } else if let Some((a, b)) = s.split_once('-') {
+            let (min, max) = (num(a)?, num(b)?);
+            if min > max {
+                bail!("`{s}` is empty, use `{max}-{min}`");
+            }
+            NumRange {
+                min: Some(min),
+                max: Some(max),
+            }
+        } else {
+            let n = num(s)?;
+            NumRange {
+                min: Some(n),
+                max: Some(n),
+            }
+        };
+        Ok(range)
+    }
+
+    fn contains(&self, n: u32) -> bool {
+        self.min.is_none_or(|min| n >= min) && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+impl Display for NumRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.min, self.max) {
+            (Some(a), Some(b)) if a == b => write!(f, "{a}"),
+            (Some(a), Some(b)) => write!(f, "{a}-{b}"),
+            (Some(a), None) => write!(f, ">={a}"),
+            (None, Some(b)) => write!(f, "<={b}"),
+            (None, None) => write!(f, ">=0"),
+        }
+    }
+}
+
+/// A `key:value` term of a query that checks a parsed field instead of
+/// the raw title.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum FieldFilter {
+    Group(String),
+    Show(String),
+    Episode(NumRange),
+    Version(NumRange),
+    Resolution(NumRange),
+    Codec(String),
+    Crc(String),
+}
+
+impl FieldFilter {
+    pub const KEYS: [&'static str; 7] = ["group", "show", "ep", "v", "res", "codec", "crc"];
+
+    pub fn parse(key: &str, value: &str) -> Result<Self> {
+        let filter = match key {
+            "group" => FieldFilter::Group(value.to_string()),
+            "show" => FieldFilter::Show(value.to_string()),
+            "ep" => FieldFilter::Episode(NumRange::parse(value)?),
+            "v" => FieldFilter::Version(NumRange::parse(value)?),
+            "res" => FieldFilter::Resolution(NumRange::parse(value)?),
+            "codec" => FieldFilter::Codec(
+                canonical_codec(value)
+                    .ok_or(anyhow!("unknown codec `{value}`"))?
+                    .to_string(),
+            ),
+            "crc" => FieldFilter::Crc(value.to_uppercase()),
+            _ => bail!("unknown field `{key}`"),
+        };
+        Ok(filter)
+    }
+
+    pub fn matches(&self, info: &ReleaseInfo) -> bool {
+        match self {
+            FieldFilter::Group(g) => info
+                .group
+                .as_ref()
+                .is_some_and(|group| normalize(group) == normalize(g)),
+            FieldFilter::Show(s) => info
+                .show
+                .as_ref()
+                .is_some_and(|show| normalize(show).contains(&normalize(s))),
+            // releases without a version tag are v1
+            FieldFilter::Version(r) => r.contains(info.version.unwrap_or(1)),
+            FieldFilter::Episode(r) => info.episode.is_some_and(|e| r.contains(e)),
+            FieldFilter::Resolution(r) => info.resolution.is_some_and(|res| r.contains(res)),
+            FieldFilter::Codec(c) => info.codec.as_ref() == Some(c),
+            FieldFilter::Crc(c) => info.crc.as_ref() == Some(c),
+        }
+    }
+
+    fn parts(&self) -> (&'static str, String) {
+        match self {
+            FieldFilter::Group(g) => ("group", g.clone()),
+            FieldFilter::Show(s) => ("show", s.clone()),
+            FieldFilter::Episode(r) => ("ep", r.to_string()),
+            FieldFilter::Version(r) => ("v", r.to_string()),
+            FieldFilter::Resolution(r) => ("res", r.to_string()),
+            FieldFilter::Codec(c) => ("codec", c.clone()),
+            FieldFilter::Crc(c) => ("crc", c.clone()),
+        }
+    }
+}
+
+impl Display for FieldFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (key, value) = self.parts();
+        if value.is_empty() || value.contains([' ', '"', '(', ')', ';']) {
+            write!(f, "{key}:\"{}\"", value.replace('"', "\\\""))
+        } else {
+            write!(f, "{key}:{value}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_anime_release() {
+        let info = ReleaseInfo::parse(
+            "[SubsPlease] Sousou no Frieren - 12v2 (1080p) [HEVC][ABCD1234].mkv",
+        );
+        assert_eq!(
+            info,
+            ReleaseInfo {
+                group: Some("SubsPlease".into()),
+                show: Some("Sousou no Frieren".into()),
+                season: None,
+                episode: Some(12),
+                version: Some(2),
+                resolution: Some(1080),
+                codec: Some("hevc".into()),
+                crc: Some("ABCD1234".into()),
+                repack: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_scene_release() {
+        let info = ReleaseInfo::parse("Show.Name.S01E03.720p.WEB.x264-GROUP");
+        assert_eq!(info.group.as_deref(), Some("GROUP"));
+        assert_eq!(info.show.as_deref(), Some("Show Name"));
+        assert_eq!(info.season, Some(1));
+        assert_eq!(info.episode, Some(3));
+        assert_eq!(info.resolution, Some(720));
+        assert_eq!(info.codec.as_deref(), Some("avc"));
+        assert!(ReleaseInfo::parse("Show.Name.S01E03.REPACK.720p.WEB.x264-GROUP").repack);
+    }
+
+    #[test]
+    fn test_field_filter() {
+        let info = ReleaseInfo::parse("[Erai-raws] One Piece - 1100 [1080p][Multiple Subtitle]");
+        assert!(FieldFilter::parse("group", "erai-raws")
+            .unwrap()
+            .matches(&info));
+        assert!(FieldFilter::parse("res", "1080p").unwrap().matches(&info));
+        assert!(FieldFilter::parse("ep", ">=1000").unwrap().matches(&info));
+        assert!(!FieldFilter::parse("ep", "1-12").unwrap().matches(&info));
+        assert!(FieldFilter::parse("v", "1").unwrap().matches(&info));
+        assert!(FieldFilter::parse("res", "big").is_err());
+        assert!(FieldFilter::parse("ep", ">4294967295").is_err());
+        assert!(FieldFilter::parse("ep", "<0").is_err());
+        assert!(FieldFilter::parse("ep", "<1")
+            .unwrap()
+            .matches(&ReleaseInfo::parse("[A] B - 00")));
+        assert!(FieldFilter::parse("ep", "10-5").is_err());
+        assert!(FieldFilter::parse("ep", "5-5").is_ok());
+    }
+}
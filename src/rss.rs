@@ -1,109 +1,610 @@
-use std::cmp::Reverse;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
-use chrono::{DateTime, FixedOffset};
-use reqwest::IntoUrl;
+use chrono::{DateTime, FixedOffset, Utc};
+use reqwest::header::{
+    HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::StatusCode;
 use rss::Channel;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 
+use crate::feed::Feed;
+use crate::persist;
+use crate::release::ReleaseInfo;
+use crate::seen::SeenSet;
 use crate::setup::load_last_seen;
-use crate::torrent::Torrent;
-
-pub async fn poll_rss(
-    link: String,
-    store_folder: String,
-    timeout: Duration,
-    fail_timeout: Duration,
-    notify_sender: Sender<Vec<RssEntry>>,
-) {
-    let mut latest_element = load_last_seen(&store_folder).unwrap();
+use crate::torrent::{format_size, ResolvedTorrent, Torrent};
+
+/// Longest wait a feed or server can ask for, so a bogus `Retry-After`
+/// or `<ttl>` doesn't stop polling.
+const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Items dated this much before the newest handled one are skipped without
+/// looking at the seen set, so forgotten ids don't come back.
+const BACKDATE_GRACE: chrono::Duration = chrono::Duration::days(7);
+/// Longest wait after failed polls, the wait doubles with each failure
+/// in a row until it gets here.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How often the bytes saved by conditional requests are logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Polls one feed and sends the items that weren't seen before. Requests
+/// are conditional on the validators of the last full response. Returns
+/// once nothing receives the items anymore.
+pub async fn poll_rss(feed: Feed, store_folder: PathBuf, notify_sender: Sender<Vec<RssEntry>>) {
+    let watermark = feed.watermark(&store_folder);
+    let cache = feed.validators(&store_folder);
+    let seen_path = feed.seen(&store_folder);
+    let mut latest_element = loop {
+        match load_last_seen(&watermark) {
+            Ok(latest) => break latest,
+            Err(e) => {
+                log::error!(
+                    "could not read the watermark of feed {}: {e}. retrying in {}s",
+                    feed.name,
+                    feed.failure.as_secs()
+                );
+                tokio::time::sleep(feed.failure).await;
+            }
+        }
+    };
+    // what the watermark file says, a failed write is retried after the next poll
+    let mut written = latest_element;
+    let mut seen = SeenSet::load(&seen_path).unwrap_or_else(|e| {
+        log::error!(
+            "starting over from the watermark of feed {}: {e}",
+            feed.name
+        );
+        None
+    });
+    let mut validators = Validators::load(&cache);
+    let client = reqwest::Client::new();
+    let (mut saved, mut reported) = (0, Instant::now());
+    let mut failures = 0;
     loop {
-        let feed = match load_rss_feed(&link).await {
+        if reported.elapsed() >= REPORT_INTERVAL {
+            if saved > 0 {
+                log::warn!(
+                    "feed {} was unchanged, saving {} in the last hour",
+                    feed.name,
+                    format_size(saved)
+                );
+            }
+            (saved, reported) = (0, Instant::now());
+        }
+        let fetch = match load_rss_feed(&client, &feed, &validators).await {
             Ok(v) => v,
             Err(e) => {
-                log::error!("error occurred while loading rss: {e}.");
-                tokio::time::sleep(fail_timeout).await;
+                failures += 1;
+                let wait = backoff(feed.failure, failures);
+                log::error!(
+                    "error occurred while loading rss feed {}: {e}. retrying in {}s",
+                    feed.name,
+                    wait.as_secs()
+                );
+                tokio::time::sleep(wait).await;
                 continue;
             }
         };
-        let mut new_entries: Vec<_> = feed
-            .into_iter()
-            .filter(|item| item.pub_date > latest_element)
-            .collect();
-        if new_entries.is_empty() {
-            tokio::time::sleep(timeout).await;
+        failures = 0;
+        let wait = fetch.wait.map_or(feed.check, |w| w.max(feed.check));
+        saved += fetch.saved;
+        let Some(items) = fetch.items else {
+            tokio::time::sleep(wait).await;
             continue;
+        };
+        let bootstrap = seen.is_none();
+        let seen = seen.get_or_insert_with(SeenSet::default);
+        let mut new_entries = unseen(items, seen, bootstrap, latest_element);
+        let found = !new_entries.is_empty();
+        if found {
+            // oldest first so episode tracking sees episodes in release order
+            new_entries.sort_by_key(|item| item.pub_date);
+
+            let new_latest = new_entries[new_entries.len() - 1].pub_date;
+            if notify_sender.send(new_entries).await.is_err() {
+                log::warn!(
+                    "stopped polling feed {}, nothing receives its items",
+                    feed.name
+                );
+                return;
+            }
+            latest_element = latest_element.max(new_latest);
+        }
+        if latest_element != written {
+            let latest_string = latest_element.to_rfc2822();
+            match persist::write_atomic(&watermark, latest_string.as_bytes()) {
+                Ok(()) => written = latest_element,
+                Err(e) => log::error!("saving the watermark of feed {} failed: {e}", feed.name),
+            }
         }
-        new_entries.sort_by_key(|item| Reverse(item.pub_date));
+        if bootstrap || found {
+            if let Err(e) = seen.save(&seen_path) {
+                log::error!("saving the seen items of feed {} failed: {e}", feed.name);
+            }
+        }
+        // only after the items were handled, a crash before refetches them
+        if fetch.validators != validators {
+            validators = fetch.validators;
+            if let Err(e) = validators.save(&cache) {
+                log::error!("saving the validators of feed {} failed: {e}", feed.name);
+            }
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
 
-        let new_latest = new_entries[0].pub_date.clone();
-        notify_sender.send(new_entries).await.unwrap();
-        let latest_string = new_latest.to_rfc2822();
-        write_latest(&store_folder, &latest_string).unwrap();
-        latest_element = new_latest;
-        tokio::time::sleep(timeout).await;
+/// The wait after `failures` failed polls in a row, doubling from
+/// `failure` up to [`MAX_BACKOFF`] or `failure` if that is longer.
+fn backoff(failure: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    failure.saturating_mul(factor).min(MAX_BACKOFF.max(failure))
+}
+
+/// The items that weren't seen yet, which are marked as seen. The first
+/// time a feed has a seen set, everything up to `latest` counts as seen.
+fn unseen(
+    items: Vec<RssEntry>,
+    seen: &mut SeenSet,
+    bootstrap: bool,
+    latest: DateTime<FixedOffset>,
+) -> Vec<RssEntry> {
+    if bootstrap {
+        for item in items.iter().filter(|i| i.pub_date <= latest) {
+            seen.insert(item.id.clone());
+        }
     }
+    // the seen set decides, the date only rules out items that are far
+    // older than anything handled, e.g. after their ids were forgotten
+    let horizon = latest - BACKDATE_GRACE;
+    items
+        .into_iter()
+        .filter(|item| item.pub_date > horizon && seen.insert(item.id.clone()))
+        .collect()
 }
 
-fn write_latest(store_folder: impl Into<PathBuf>, latest: &str) -> Result<()> {
-    let mut buf = store_folder.into();
-    buf.push("last.txt");
-    let mut file = std::fs::OpenOptions::new()
-        .write(true)
-        .append(false)
-        .truncate(true)
-        .open(buf)?;
-    file.write_all(latest.as_bytes())?;
-    Ok(())
+/// What identifies the last full response of a feed, sent back so the
+/// server can answer with 304 if nothing changed.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Size of the response body in bytes.
+    size: u64,
+}
+
+impl Validators {
+    fn load(path: &Path) -> Self {
+        if !persist::exists(path) {
+            return Validators::default();
+        }
+        persist::read_recover(path, |data| Ok(serde_json::from_slice(data)?)).unwrap_or_else(|e| {
+            log::error!("ignoring {}: {e}", path.display());
+            Validators::default()
+        })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        persist::write_atomic(path, &serde_json::to_vec(self)?)
+    }
+}
+
+/// The outcome of one poll.
+pub struct Fetch {
+    /// `None` if the feed didn't change or the server asked to back off.
+    pub items: Option<Vec<RssEntry>>,
+    /// To send with the next request.
+    pub validators: Validators,
+    /// The longest wait the server or the feed asked for.
+    pub wait: Option<Duration>,
+    /// Bytes not transferred because the server answered 304.
+    pub saved: u64,
 }
 
 pub struct RssEntry {
+    /// Identifies the item within its feed.
+    pub id: String,
+    /// Name of the feed the item is from.
+    pub feed: String,
     pub title: String,
     pub link: String,
     pub pub_date: DateTime<FixedOffset>,
+    pub release: ReleaseInfo,
 }
 
-pub async fn load_rss_feed(link: impl IntoUrl) -> Result<Vec<RssEntry>> {
-    let content = reqwest::get(link).await?.bytes().await?;
-    let channel = Channel::read_from(&content[..])?;
-    let mut entries = Vec::with_capacity(channel.items.len());
-    for (i, item) in channel.items.into_iter().enumerate() {
-        if item.title.is_none() {
-            log::error!("item {i} in rss feed has no title.");
-            continue;
+pub async fn load_rss_feed(
+    client: &reqwest::Client,
+    feed: &Feed,
+    validators: &Validators,
+) -> Result<Fetch> {
+    let mut request = client.get(&feed.url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    let wait = retry_after(response.headers());
+    let unchanged = |wait, saved| Fetch {
+        items: None,
+        validators: validators.clone(),
+        wait,
+        saved,
+    };
+    match response.status() {
+        StatusCode::NOT_MODIFIED => return Ok(unchanged(wait, validators.size)),
+        status @ (StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) => {
+            log::warn!("feed {} asks to back off ({status})", feed.name);
+            return Ok(unchanged(wait.or(Some(feed.failure)), 0));
         }
-        let title = item.title.unwrap();
-        if item.link.is_none() {
-            log::error!("item {i}:{title} in rss feed does not have a link");
-            continue;
+        _ => {}
+    }
+    let response = response.error_for_status()?;
+    let header = |name| {
+        let value = response.headers().get(name)?.to_str().ok()?;
+        Some(value.to_string())
+    };
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+    let content = response.bytes().await?;
+    let parsed = parse_feed(&feed.name, &content)?;
+    Ok(Fetch {
+        items: Some(parsed.entries),
+        validators: Validators {
+            etag,
+            last_modified,
+            size: content.len() as u64,
+        },
+        wait: wait.max(parsed.ttl),
+        saved: 0,
+    })
+}
+
+/// `Retry-After` in seconds or as HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let wait = match value.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let at = DateTime::parse_from_rfc2822(value).ok()?;
+            (at.with_timezone(&Utc) - Utc::now()).to_std().ok()?
         }
-        let link = item.link.unwrap();
-        let pub_date = chrono::DateTime::parse_from_rfc2822(&item.pub_date.unwrap());
-        if pub_date.is_err() {
-            log::error!("item {i}:{title} in rss feed has an invalid publication date");
+    };
+    Some(wait.min(MAX_WAIT))
+}
+
+/// The formats a feed can be in, told apart by its content.
+#[derive(Debug, PartialEq)]
+enum Format {
+    Rss,
+    Atom,
+    Json,
+}
+
+fn detect(content: &[u8]) -> Format {
+    let text = String::from_utf8_lossy(content);
+    let text = text.trim_start_matches(|c: char| c == '\u{feff}' || c.is_whitespace());
+    if text.starts_with('{') {
+        return Format::Json;
+    }
+    // the first element that isn't the xml declaration, a comment or a doctype
+    let root = text
+        .split('<')
+        .skip(1)
+        .find(|tag| !tag.starts_with('?') && !tag.starts_with('!'))
+        .and_then(|tag| tag.split(|c: char| c.is_whitespace() || c == '>').next())
+        .unwrap_or_default();
+    match root.rsplit(':').next() {
+        Some("feed") => Format::Atom,
+        _ => Format::Rss,
+    }
+}
+
+/// An item as found in the feed, checked before it becomes an [`RssEntry`].
+struct RawItem {
+    /// Info hash or guid, the link is used if there is neither.
+    id: Option<String>,
+    title: Option<String>,
+    link: Option<String>,
+    /// `None` if missing or invalid.
+    pub_date: Option<DateTime<FixedOffset>>,
+}
+
+struct ParsedFeed {
+    entries: Vec<RssEntry>,
+    /// How long the feed may be cached, from RSS `<ttl>`.
+    ttl: Option<Duration>,
+}
+
+/// Parses an RSS 2.0, Atom or JSON Feed document, items without title, link
+/// or a valid date are skipped.
+fn parse_feed(feed_name: &str, content: &[u8]) -> Result<ParsedFeed> {
+    let (items, ttl) = match detect(content) {
+        Format::Rss => rss_items(content)?,
+        Format::Atom => (atom_items(content)?, None),
+        Format::Json => (json_items(content)?, None),
+    };
+    let mut entries = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let Some(title) = item.title else {
+            log::error!("item {i} in feed {feed_name} has no title.");
             continue;
-        }
-        let pub_date = pub_date?;
+        };
+        let Some(link) = item.link else {
+            log::error!("item {i}:{title} in feed {feed_name} does not have a link");
+            continue;
+        };
+        let Some(pub_date) = item.pub_date else {
+            log::error!("item {i}:{title} in feed {feed_name} has an invalid publication date");
+            continue;
+        };
         entries.push(RssEntry {
+            id: item.id.unwrap_or_else(|| link.clone()),
+            feed: feed_name.to_string(),
+            release: ReleaseInfo::parse(&title),
             title,
             link,
             pub_date,
         })
     }
     if entries.is_empty() {
-        bail!("RSS feed returned no valid items");
+        bail!("feed returned no valid items");
     }
-    Ok(entries)
+    Ok(ParsedFeed { entries, ttl })
+}
+
+/// The items and the `<ttl>` in minutes.
+fn rss_items(content: &[u8]) -> Result<(Vec<RawItem>, Option<Duration>)> {
+    let channel = Channel::read_from(content)?;
+    let ttl = channel
+        .ttl
+        .and_then(|minutes| minutes.trim().parse::<u64>().ok())
+        .map(|minutes| Duration::from_secs(minutes * 60).min(MAX_WAIT));
+    let items = channel
+        .items
+        .into_iter()
+        .map(|item| RawItem {
+            // trackers like nyaa add the info hash as <nyaa:infoHash>
+            id: item
+                .extensions
+                .values()
+                .filter_map(|ext| ext.get("infoHash"))
+                .flatten()
+                .find_map(|e| e.value.as_ref().map(|v| v.trim().to_lowercase()))
+                .or(item.guid.map(|g| g.value)),
+            pub_date: item
+                .pub_date
+                .and_then(|d| DateTime::parse_from_rfc2822(&d).ok()),
+            title: item.title,
+            link: item.link,
+        })
+        .collect();
+    Ok((items, ttl))
+}
+
+fn atom_items(content: &[u8]) -> Result<Vec<RawItem>> {
+    let feed = atom_syndication::Feed::read_from(content)?;
+    Ok(feed
+        .entries()
+        .iter()
+        .map(|entry| {
+            let links = entry.links();
+            // trackers link the .torrent as enclosure and the page as alternate
+            let link = links
+                .iter()
+                .find(|l| l.mime_type() == Some(TORRENT_MIME))
+                .or(links.iter().find(|l| l.rel() == "alternate"))
+                .or(links.first());
+            RawItem {
+                id: Some(entry.id().to_string()).filter(|id| !id.is_empty()),
+                title: Some(entry.title().as_str().to_string()).filter(|t| !t.is_empty()),
+                link: link.map(|l| l.href().to_string()),
+                pub_date: Some(*entry.published().unwrap_or(entry.updated())),
+            }
+        })
+        .collect())
+}
+
+const TORRENT_MIME: &str = "application/x-bittorrent";
+
+/// The parts of a JSON Feed (<https://jsonfeed.org>) that are used.
+#[derive(Deserialize)]
+struct JsonFeed {
+    items: Vec<JsonItem>,
+}
+
+#[derive(Deserialize)]
+struct JsonItem {
+    /// A string by the spec, but some feeds use numbers.
+    id: Option<serde_json::Value>,
+    title: Option<String>,
+    url: Option<String>,
+    date_published: Option<String>,
+    date_modified: Option<String>,
+    #[serde(default)]
+    attachments: Vec<JsonAttachment>,
+}
+
+#[derive(Deserialize)]
+struct JsonAttachment {
+    url: String,
+    mime_type: Option<String>,
+}
+
+fn json_items(content: &[u8]) -> Result<Vec<RawItem>> {
+    let feed: JsonFeed = serde_json::from_slice(content)?;
+    Ok(feed
+        .items
+        .into_iter()
+        .map(|item| {
+            let torrent = item
+                .attachments
+                .into_iter()
+                .find(|a| a.mime_type.as_deref() == Some(TORRENT_MIME));
+            RawItem {
+                id: item.id.map(|id| match id {
+                    serde_json::Value::String(s) => s,
+                    other => other.to_string(),
+                }),
+                title: item.title,
+                link: torrent.map(|a| a.url).or(item.url),
+                pub_date: item
+                    .date_published
+                    .or(item.date_modified)
+                    .and_then(|d| DateTime::parse_from_rfc3339(&d).ok()),
+            }
+        })
+        .collect())
 }
 
 impl RssEntry {
-    pub async fn get_magnet_for_entry(&self) -> Result<String> {
+    pub async fn resolve_torrent(&self) -> Result<ResolvedTorrent> {
         let response = reqwest::get(&self.link).await?;
         let data = response.bytes().await?;
         let torrent = Torrent::from_bytes(&data)?;
-        torrent.create_magnet_link()
+        torrent.resolve()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:nyaa="https://nyaa.si/xmlns/nyaa"><channel><title>nyaa</title><ttl>10</ttl>
+<item><title>[SubsPlease] Frieren - 12 (1080p)</title><link>https://nyaa.si/download/1.torrent</link>
+<guid isPermaLink="true">https://nyaa.si/view/1</guid><nyaa:infoHash>ABCDEF</nyaa:infoHash>
+<pubDate>Fri, 01 Mar 2024 17:00:00 +0000</pubDate></item>
+<item><title>no date</title><link>https://nyaa.si/download/2.torrent</link></item>
+</channel></rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- tracker export -->
+<feed xmlns="http://www.w3.org/2005/Atom"><title>tracker</title><id>urn:t</id>
+<updated>2024-03-01T18:00:00Z</updated>
+<entry><title>[SubsPlease] Frieren - 12 (1080p)</title><id>urn:1</id>
+<link rel="alternate" href="https://tracker.example/view/1"/>
+<link rel="enclosure" type="application/x-bittorrent" href="https://tracker.example/1.torrent"/>
+<published>2024-03-01T17:00:00+01:00</published><updated>2024-03-01T18:00:00Z</updated></entry>
+<entry><title>[Erai-raws] Frieren - 12</title><id>urn:2</id>
+<link href="https://tracker.example/view/2"/><updated>2024-03-01T18:30:00Z</updated></entry>
+</feed>"#;
+
+    const JSON: &str = r#"{"version": "https://jsonfeed.org/version/1.1", "title": "tracker",
+"items": [
+  {"id": "1", "title": "[SubsPlease] Frieren - 12 (1080p)", "url": "https://tracker.example/view/1",
+   "date_published": "2024-03-01T17:00:00Z",
+   "attachments": [{"url": "https://tracker.example/1.torrent", "mime_type": "application/x-bittorrent"}]},
+  {"id": 2, "title": "[Erai-raws] Frieren - 12", "url": "https://tracker.example/view/2",
+   "date_modified": "2024-03-01T18:30:00Z"},
+  {"id": "3", "content_text": "no title", "url": "https://tracker.example/view/3"}
+]}"#;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(RSS.as_bytes()), Format::Rss);
+        assert_eq!(detect(ATOM.as_bytes()), Format::Atom);
+        assert_eq!(
+            detect(b"\xef\xbb\xbf<atom:feed xmlns:atom=\"\">"),
+            Format::Atom
+        );
+        assert_eq!(detect(format!("\n {JSON}").as_bytes()), Format::Json);
+    }
+
+    #[test]
+    fn test_parse_formats() {
+        let rss = parse_feed("nyaa", RSS.as_bytes()).unwrap();
+        assert_eq!(rss.ttl, Some(Duration::from_secs(600)));
+        let rss = rss.entries;
+        assert_eq!(rss.len(), 1);
+        assert_eq!(rss[0].link, "https://nyaa.si/download/1.torrent");
+        assert_eq!(rss[0].id, "abcdef");
+
+        let atom = parse_feed("tracker", ATOM.as_bytes()).unwrap().entries;
+        assert_eq!(atom.len(), 2);
+        assert_eq!(atom[0].link, "https://tracker.example/1.torrent");
+        assert_eq!(atom[0].pub_date.to_rfc3339(), "2024-03-01T17:00:00+01:00");
+        assert_eq!(atom[1].link, "https://tracker.example/view/2");
+        assert_eq!(atom[1].id, "urn:2");
+        assert_eq!(atom[1].release.group.as_deref(), Some("Erai-raws"));
+
+        let json = parse_feed("tracker", JSON.as_bytes()).unwrap().entries;
+        assert_eq!(json.len(), 2);
+        assert_eq!(json[0].link, "https://tracker.example/1.torrent");
+        assert_eq!(json[1].pub_date.to_rfc3339(), "2024-03-01T18:30:00+00:00");
+        assert_eq!(json[1].feed, "tracker");
+        assert_eq!(json[1].id, "2");
+
+        assert!(parse_feed("tracker", b"{\"items\": []}").is_err());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        let soon = (Utc::now() + chrono::Duration::minutes(10)).to_rfc2822();
+        headers.insert(RETRY_AFTER, soon.parse().unwrap());
+        let wait = retry_after(&headers).unwrap();
+        assert!(wait > Duration::from_secs(590) && wait <= Duration::from_secs(600));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2099 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(MAX_WAIT));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let failure = Duration::from_secs(180);
+        assert_eq!(backoff(failure, 1), failure);
+        assert_eq!(backoff(failure, 3), failure * 4);
+        assert_eq!(backoff(failure, 10), MAX_BACKOFF);
+        assert_eq!(backoff(failure, u32::MAX), MAX_BACKOFF);
+        let long = Duration::from_secs(2 * 60 * 60);
+        assert_eq!(backoff(long, 5), long);
+    }
+
+    #[test]
+    fn test_unseen() {
+        let item = |id: &str, date: &str| RssEntry {
+            id: id.into(),
+            feed: "nyaa".into(),
+            title: format!("[SubsPlease] Frieren - {id}"),
+            link: String::new(),
+            pub_date: DateTime::parse_from_rfc3339(date).unwrap(),
+            release: ReleaseInfo::default(),
+        };
+        let ids = |items: Vec<RssEntry>| items.into_iter().map(|i| i.id).collect::<Vec<_>>();
+        let latest = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap();
+        let mut seen = SeenSet::default();
+
+        // before the seen set, the watermark decided
+        let first = vec![
+            item("1", "2024-03-01T11:00:00Z"),
+            item("2", "2024-03-01T12:00:00Z"),
+            item("3", "2024-03-01T12:05:00Z"),
+        ];
+        assert_eq!(ids(unseen(first, &mut seen, true, latest)), vec!["3"]);
+
+        let latest = DateTime::parse_from_rfc3339("2024-03-01T12:05:00Z").unwrap();
+        let second = vec![
+            item("2", "2024-03-01T12:00:00Z"),
+            item("3", "2024-03-01T12:05:00Z"),
+            // same timestamp as the newest handled item
+            item("4", "2024-03-01T12:05:00Z"),
+            // backdated
+            item("5", "2024-02-29T09:00:00Z"),
+            // older than the grace
+            item("6", "2024-02-01T09:00:00Z"),
+            item("4", "2024-03-01T12:05:00Z"),
+        ];
+        assert_eq!(
+            ids(unseen(second, &mut seen, false, latest)),
+            vec!["4", "5"]
+        );
     }
 }
@@ -0,0 +1,75 @@
+use crate::persist;
+use anyhow::Result;
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+
+/// How many item ids are remembered per feed, far more than a feed shows
+/// at once.
+const LIMIT: usize = 2000;
+
+/// The ids of the items of a feed that were already handled, the oldest
+/// are forgotten once there are more than [`LIMIT`].
+#[derive(Default)]
+pub struct SeenSet {
+    /// Oldest first.
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenSet {
+    /// `None` if there is no file yet, e.g. on the first poll after an update
+    /// from the plain watermark.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !persist::exists(path) {
+            return Ok(None);
+        }
+        let order: Vec<String> = persist::read_recover(path, |d| Ok(serde_json::from_slice(d)?))?;
+        let mut seen = SeenSet::default();
+        for id in order {
+            seen.insert(id);
+        }
+        Ok(Some(seen))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        persist::write_atomic(path, &serde_json::to_vec(&self.order)?)
+    }
+
+    /// Returns false if the id was already seen.
+    pub fn insert(&mut self, id: String) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > LIMIT {
+            let oldest = self.order.pop_front().unwrap();
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::TempDir;
+
+    #[test]
+    fn test_bounded_and_persisted() {
+        let dir = TempDir::new("seen");
+        let path = dir.join("seen.json");
+        assert!(SeenSet::load(&path).unwrap().is_none());
+        let mut seen = SeenSet::default();
+        for i in 0..=LIMIT {
+            seen.insert(i.to_string());
+        }
+        assert!(!seen.insert("5".into()));
+        assert!(!seen.ids.contains("0"));
+        assert!(seen.ids.contains("1") && seen.ids.contains(&LIMIT.to_string()));
+
+        seen.save(&path).unwrap();
+        let loaded = SeenSet::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.order, seen.order);
+        assert!(loaded.ids.contains("5"));
+    }
+}
@@ -1,56 +1,119 @@
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, FixedOffset};
-use tokio::sync::RwLock;
+use serenity::prelude::{Context, TypeMapKey};
 
-use crate::store::{Entry, UserStore};
+use crate::feed::Feed;
+use crate::format;
+use crate::persist;
+use crate::snapshot::Snapshots;
+use crate::store::{Entry, StoreHandle, UserStore};
+use crate::transfer::PendingImports;
+use std::sync::Arc;
 
-static USER_STORE: OnceLock<RwLock<UserStore>> = OnceLock::new();
+/// Key of the store handle in the serenity [`serenity::prelude::TypeMap`].
+pub struct StoreKey;
 
-pub fn get_user_store() -> &'static RwLock<UserStore> {
-    USER_STORE.get_or_init(|| panic!("user store accessed before setup"))
+impl TypeMapKey for StoreKey {
+    type Value = StoreHandle;
 }
 
-pub fn setup_resources(path: impl AsRef<Path>) -> Result<()> {
+/// Key of the snapshots of the store folder.
+pub struct SnapshotsKey;
+
+impl TypeMapKey for SnapshotsKey {
+    type Value = Arc<Snapshots>;
+}
+
+/// Key of the configured feeds.
+pub struct FeedsKey;
+
+impl TypeMapKey for FeedsKey {
+    type Value = Arc<Vec<Feed>>;
+}
+
+/// Key of the imports waiting for confirmation.
+pub struct ImportsKey;
+
+impl TypeMapKey for ImportsKey {
+    type Value = Arc<PendingImports>;
+}
+
+/// The store handle from the client's data.
+pub async fn user_store(ctx: &Context) -> Result<StoreHandle> {
+    let data = ctx.data.read().await;
+    data.get::<StoreKey>()
+        .cloned()
+        .ok_or(anyhow!("user store is not set up"))
+}
+
+pub async fn feeds(ctx: &Context) -> Result<Arc<Vec<Feed>>> {
+    let data = ctx.data.read().await;
+    data.get::<FeedsKey>()
+        .cloned()
+        .ok_or(anyhow!("feeds are not set up"))
+}
+
+pub async fn pending_imports(ctx: &Context) -> Result<Arc<PendingImports>> {
+    let data = ctx.data.read().await;
+    data.get::<ImportsKey>()
+        .cloned()
+        .ok_or(anyhow!("imports are not set up"))
+}
+
+pub async fn snapshots(ctx: &Context) -> Result<Arc<Snapshots>> {
+    let data = ctx.data.read().await;
+    data.get::<SnapshotsKey>()
+        .cloned()
+        .ok_or(anyhow!("snapshots are not set up"))
+}
+
+pub fn setup_resources(path: impl AsRef<Path>, backend: &str, feeds: &[Feed]) -> Result<UserStore> {
     let path = path.as_ref();
     let mut user_store_path = path.to_path_buf();
     user_store_path.push("user.bin");
-    let mut last_seen = path.to_path_buf();
-    last_seen.push("last.txt");
 
     if !path.exists() {
         std::fs::create_dir(path)?;
     }
 
-    if !user_store_path.exists() {
-        let empty: Vec<Entry> = Vec::new();
-        let data = bincode::serialize(&empty)?;
-        let mut file = File::create(&user_store_path)?;
-        file.write_all(&data)?;
-    }
-
-    let us = UserStore::from_path(user_store_path)?;
-    USER_STORE.get_or_init(|| RwLock::new(us));
+    let us = match backend {
+        "file" => {
+            if !persist::exists(&user_store_path) {
+                let empty: Vec<Entry> = Vec::new();
+                let data = format::encode(&empty, &Default::default(), 1)?;
+                persist::write_atomic(&user_store_path, &data)?;
+            }
+            UserStore::migrate(&user_store_path)?;
+            UserStore::from_path(user_store_path)?
+        }
+        "sqlite" => UserStore::from_sqlite(path.join("makima.db"), user_store_path)?,
+        "memory" => UserStore::in_memory(),
+        _ => bail!("unknown STORE_BACKEND {backend}, use file, sqlite or memory"),
+    };
 
-    if !last_seen.exists() {
-        let mut file = File::create(&last_seen)?;
-        let near_past = chrono::Utc::now() - chrono::Duration::hours(4);
-        file.write_all(near_past.to_rfc2822().as_bytes())?;
+    // the single watermark from before there were several feeds
+    let legacy = path.join("last.txt");
+    for feed in feeds {
+        let watermark = feed.watermark(path);
+        if !persist::exists(&watermark) {
+            let start = match load_last_seen(&legacy) {
+                Ok(last) => last.to_rfc2822(),
+                Err(_) => (chrono::Utc::now() - chrono::Duration::hours(4)).to_rfc2822(),
+            };
+            persist::write_atomic(&watermark, start.as_bytes())?;
+        }
+        // recovers the watermark from its backup before the poller needs it
+        load_last_seen(&watermark)?;
     }
 
-    Ok(())
+    Ok(us)
 }
 
-pub fn load_last_seen(p: impl Into<PathBuf>) -> Result<DateTime<FixedOffset>> {
-    let mut p = p.into();
-    p.push("last.txt");
-    let mut file = File::open(&p)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let pub_date = chrono::DateTime::parse_from_rfc2822(&contents)?;
-    Ok(pub_date)
+pub fn load_last_seen(p: &Path) -> Result<DateTime<FixedOffset>> {
+    persist::read_recover(p, |data| {
+        let pub_date = chrono::DateTime::parse_from_rfc2822(std::str::from_utf8(data)?)?;
+        Ok(pub_date)
+    })
 }
@@ -0,0 +1,314 @@
+use crate::format::{self, Contents};
+use crate::history;
+use crate::persist;
+use crate::setup;
+use crate::sqlite::SqliteBackend;
+use crate::store::StoreHandle;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Subfolder of the store folder the snapshots are kept in.
+const DIR: &str = "snapshots";
+const NAME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Which snapshots survive pruning.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// The newest snapshots that are always kept.
+    pub keep: usize,
+    /// On top of those, the newest snapshot of each of the last days that
+    /// have snapshots.
+    pub keep_days: usize,
+}
+
+impl Retention {
+    /// The snapshots to delete out of `taken`, which is sorted newest first.
+    fn expired(&self, taken: &[DateTime<Utc>]) -> Vec<DateTime<Utc>> {
+        let mut days = HashSet::new();
+        taken
+            .iter()
+            .enumerate()
+            .filter(|(i, t)| {
+                let newest_of_day = days.len() < self.keep_days && days.insert(t.date_naive());
+                *i >= self.keep && !newest_of_day
+            })
+            .map(|(_, t)| *t)
+            .collect()
+    }
+}
+
+pub struct Snapshot {
+    pub name: String,
+    pub taken: DateTime<Utc>,
+    /// Size of all files in it.
+    pub bytes: u64,
+}
+
+/// Timestamped copies of every file in the store folder, taken
+/// periodically and before restores.
+pub struct Snapshots {
+    folder: PathBuf,
+    /// The `STORE_BACKEND` the store was opened with.
+    backend: String,
+    retention: Retention,
+}
+
+impl Snapshots {
+    pub fn new(folder: impl Into<PathBuf>, backend: &str, retention: Retention) -> Self {
+        Snapshots {
+            folder: folder.into(),
+            backend: backend.to_string(),
+            retention,
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.folder.join(DIR)
+    }
+
+    /// Copies the files of the store folder into a new snapshot, returns
+    /// its name. Callers make sure the store isn't written meanwhile.
+    pub fn create(&self, now: DateTime<Utc>) -> Result<String> {
+        let name = now.format(NAME_FORMAT).to_string();
+        let target = self.dir().join(&name);
+        if target.exists() {
+            return Ok(name);
+        }
+        // copied to a hidden folder first so a crash leaves no partial snapshot
+        let partial = self.dir().join(format!(".{name}"));
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        std::fs::create_dir_all(&partial)?;
+        for file in files(&self.folder)? {
+            std::fs::copy(&file, partial.join(file.file_name().unwrap()))?;
+        }
+        std::fs::rename(&partial, &target)?;
+        Ok(name)
+    }
+
+    /// The snapshots, newest first.
+    pub fn list(&self) -> Result<Vec<Snapshot>> {
+        if !self.dir().exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for dir in std::fs::read_dir(self.dir())? {
+            let dir = dir?;
+            let name = dir.file_name().to_string_lossy().to_string();
+            let Ok(taken) = NaiveDateTime::parse_from_str(&name, NAME_FORMAT) else {
+                continue;
+            };
+            let mut bytes = 0;
+            for file in files(&dir.path())? {
+                bytes += file.metadata()?.len();
+            }
+            snapshots.push(Snapshot {
+                name,
+                taken: taken.and_utc(),
+                bytes,
+            });
+        }
+        snapshots.sort_by_key(|s| std::cmp::Reverse(s.taken));
+        Ok(snapshots)
+    }
+
+    /// Deletes the snapshots the retention doesn't keep, returns how many.
+    pub fn prune(&self) -> Result<usize> {
+        let snapshots = self.list()?;
+        let taken: Vec<DateTime<Utc>> = snapshots.iter().map(|s| s.taken).collect();
+        let expired = self.retention.expired(&taken);
+        for s in snapshots.iter().filter(|s| expired.contains(&s.taken)) {
+            std::fs::remove_dir_all(self.dir().join(&s.name))?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Checks that every file the store reads from the snapshot
+    /// deserializes, returns what the current backend would load.
+    pub fn validate(&self, name: &str) -> Result<Contents> {
+        let dir = self.dir().join(name);
+        if NaiveDateTime::parse_from_str(name, NAME_FORMAT).is_err() || !dir.is_dir() {
+            bail!("there is no snapshot `{name}`");
+        }
+        let mut file = read_if_exists(&dir.join("user.bin"), |data| {
+            Ok(format::decode(data)?.contents)
+        })
+        .map_err(|e| anyhow!("user.bin: {e}"))?;
+        let history = read_if_exists(&dir.join(history::FILE_NAME), history::parse)
+            .map_err(|e| anyhow!("{}: {e}", history::FILE_NAME))?;
+        // user.bin older than version 7 has its history inside
+        if let (Some(contents), Some((history, _))) = (&mut file, history) {
+            if contents.history.is_empty() {
+                contents.history = history;
+            }
+        }
+        let db = match dir.join("makima.db") {
+            p if p.exists() => {
+                // opening may upgrade the database, so a copy is opened
+                let copy = self.dir().join(".validate.db");
+                std::fs::copy(&p, &copy)?;
+                let opened = SqliteBackend::open(&copy).map(|(_, contents)| contents);
+                std::fs::remove_file(&copy)?;
+                Some(opened.map_err(|e| anyhow!("makima.db: {e}"))?)
+            }
+            _ => None,
+        };
+        for file in files(&dir)? {
+            let name = file.file_name().unwrap().to_string_lossy().to_string();
+            if name.starts_with("last") && name.ends_with(".txt") {
+                setup::load_last_seen(&file).map_err(|e| anyhow!("{name}: {e}"))?;
+            }
+        }
+        match self.backend.as_str() {
+            "sqlite" => db.or(file),
+            "file" => file,
+            _ => None,
+        }
+        .ok_or(anyhow!(
+            "the snapshot has nothing the {} backend can load",
+            self.backend
+        ))
+    }
+
+    /// Validates the snapshot and loads it into the running store, after
+    /// taking a snapshot of the current state. The watermarks are left alone as
+    /// the pollers keep their own position, returns the new snapshot's name.
+    pub async fn restore(&self, name: &str, store: &StoreHandle) -> Result<String> {
+        let contents = self.validate(name)?;
+        let mut store = store.write().await;
+        store.flush()?;
+        let before = tokio::task::block_in_place(|| self.create(Utc::now()))?;
+        store.replace(contents)?;
+        store.flush()?;
+        Ok(before)
+    }
+
+    /// Validates the snapshot and copies its files back into the store
+    /// folder, for when the bot isn't running. Returns the name of the
+    /// snapshot of the state before.
+    pub fn restore_files(&self, name: &str) -> Result<String> {
+        self.validate(name)?;
+        let before = self.create(Utc::now())?;
+        for file in files(&self.dir().join(name))? {
+            persist::write_atomic(
+                &self.folder.join(file.file_name().unwrap()),
+                &std::fs::read(&file)?,
+            )?;
+        }
+        Ok(before)
+    }
+}
+
+/// Takes a snapshot every `interval` and prunes the old ones, a zero
+/// interval turns periodic snapshots off.
+pub async fn snapshot_loop(snapshots: Arc<Snapshots>, store: StoreHandle, interval: Duration) {
+    if interval.is_zero() {
+        return std::future::pending().await;
+    }
+    loop {
+        tokio::time::sleep(interval).await;
+        // the write lock keeps the store from changing while it is copied
+        let mut guard = store.write().await;
+        if let Err(e) = guard.flush() {
+            log::error!("not taking a snapshot, writing the store failed: {e}");
+            continue;
+        }
+        let result = tokio::task::block_in_place(|| {
+            snapshots.create(Utc::now())?;
+            snapshots.prune()
+        });
+        drop(guard);
+        if let Err(e) = result {
+            log::error!("taking a snapshot failed: {e}");
+        }
+    }
+}
+
+/// The regular files directly in `dir`, without temp files and backups.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_none_or(|e| e != "tmp" && e != "bak") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn read_if_exists<T>(path: &Path, parse: impl Fn(&[u8]) -> Result<T>) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(parse(&std::fs::read(path)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::TempDir;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_retention() {
+        let taken = vec![
+            utc("2024-05-03T12:00:00Z"),
+            utc("2024-05-03T06:00:00Z"),
+            utc("2024-05-03T00:00:00Z"),
+            utc("2024-05-02T18:00:00Z"),
+            utc("2024-05-02T06:00:00Z"),
+            utc("2024-05-01T18:00:00Z"),
+            utc("2024-04-30T18:00:00Z"),
+        ];
+        let retention = Retention {
+            keep: 2,
+            keep_days: 3,
+        };
+        assert_eq!(
+            retention.expired(&taken),
+            vec![
+                utc("2024-05-03T00:00:00Z"),
+                utc("2024-05-02T06:00:00Z"),
+                utc("2024-04-30T18:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_files() {
+        let dir = TempDir::new("snapshot");
+        let bin = dir.join("user.bin");
+        let last = dir.join("last.txt");
+        std::fs::write(&bin, format::encode(&[], &Default::default(), 1).unwrap()).unwrap();
+        std::fs::write(&last, "Wed, 01 May 2024 12:00:00 +0000").unwrap();
+        let snapshots = Snapshots::new(
+            dir.to_path_buf(),
+            "file",
+            Retention {
+                keep: 1,
+                keep_days: 0,
+            },
+        );
+
+        let first = snapshots.create(utc("2024-05-01T12:00:00Z")).unwrap();
+        std::fs::write(&bin, b"broken by a bad deploy").unwrap();
+        let broken = snapshots.create(utc("2024-05-02T12:00:00Z")).unwrap();
+        assert_eq!(snapshots.list().unwrap().len(), 2);
+        assert!(snapshots.validate(&broken).is_err());
+        assert!(snapshots.validate("20240101-000000").is_err());
+
+        snapshots.restore_files(&first).unwrap();
+        assert!(format::decode(&std::fs::read(&bin).unwrap()).is_ok());
+        // the restore took a snapshot of the broken state first
+        assert_eq!(snapshots.list().unwrap().len(), 3);
+        assert_eq!(snapshots.prune().unwrap(), 2);
+    }
+}
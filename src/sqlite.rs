@@ -0,0 +1,394 @@
+use crate::format::{self, Contents};
+use crate::history::{self, Record, Status};
+use crate::profile::Profile;
+use crate::storage::{State, Storage};
+use crate::store::Entry;
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    uid INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid INTEGER NOT NULL REFERENCES users(uid),
+    entry BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS subscriptions_uid ON subscriptions(uid);
+CREATE TABLE IF NOT EXISTS settings (
+    uid INTEGER NOT NULL REFERENCES users(uid),
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (uid, key)
+);
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid INTEGER NOT NULL,
+    subscription TEXT NOT NULL,
+    title TEXT NOT NULL,
+    link TEXT NOT NULL,
+    magnet TEXT NOT NULL,
+    sent_at TEXT NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS history_uid ON history(uid);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+
+/// Keeps subscriptions as one row each, so adding or removing one doesn't
+/// rewrite all of them. Matching still runs on the entries in memory.
+pub struct SqliteBackend {
+    // rusqlite connections aren't Sync, the store is shared behind a RwLock
+    conn: Mutex<Connection>,
+    /// Row ids in the same order as the store's entries.
+    rows: Vec<i64>,
+    /// Whether the database was ever filled, by an import of `user.bin`,
+    /// a restore or the first subscription.
+    imported: bool,
+}
+
+impl SqliteBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Contents)> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        // databases from before the pragma was set hold version 1 entries
+        let version: u16 = match conn.query_row("PRAGMA user_version", [], |r| r.get(0))? {
+            0 => 1,
+            v => v,
+        };
+        if version > format::VERSION {
+            bail!(
+                "database has format version {version}, this build only reads up to {}",
+                format::VERSION
+            );
+        }
+        let mut rows = Vec::new();
+        let mut blobs = Vec::new();
+        {
+            let mut stmt = conn.prepare("SELECT id, entry FROM subscriptions ORDER BY id")?;
+            let mut query = stmt.query([])?;
+            while let Some(row) = query.next()? {
+                rows.push(row.get::<_, i64>(0)?);
+                blobs.push(row.get::<_, Vec<u8>>(1)?);
+            }
+        }
+        // the rows concatenated are a bincode Vec of entries, from version
+        // 3 on followed by the profiles, in 4 to 6 by the history and from
+        // 6 on by the next id, which live in their own tables here. An
+        // empty Vec and a zero id are both encoded as a zero u64.
+        let mut payload = (blobs.len() as u64).to_le_bytes().to_vec();
+        blobs.iter().for_each(|b| payload.extend(b));
+        for sections in [3..=u16::MAX, 4..=6, 6..=u16::MAX] {
+            if sections.contains(&version) {
+                payload.extend(0u64.to_le_bytes());
+            }
+        }
+        let mut contents = format::upgrade(version, payload)?;
+        let stored: Option<u64> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'next_id'", [], |r| {
+                r.get(0)
+            })
+            .optional()?;
+        contents.next_id = contents.next_id.max(stored.unwrap_or(0));
+        // databases from before the marker only had subscriptions if filled
+        let imported = conn
+            .query_row("SELECT value FROM meta WHERE key = 'imported'", [], |r| {
+                r.get::<_, i64>(0)
+            })
+            .optional()?
+            .is_some()
+            || !rows.is_empty();
+        let entries = &contents.entries;
+        let tx = conn.transaction()?;
+        Self::write_next_id(&tx, contents.next_id)?;
+        if imported {
+            Self::write_imported(&tx)?;
+        }
+        if version < format::VERSION {
+            for (id, e) in rows.iter().zip(entries) {
+                tx.execute(
+                    "UPDATE subscriptions SET entry = ?1 WHERE id = ?2",
+                    params![bincode::serialize(e)?, id],
+                )?;
+            }
+        }
+        tx.pragma_update(None, "user_version", format::VERSION)?;
+        tx.commit()?;
+        contents.profiles = Self::load_profiles(&conn)?;
+        contents.history = Self::load_history(&conn)?;
+        let backend = SqliteBackend {
+            conn: Mutex::new(conn),
+            rows,
+            imported,
+        };
+        Ok((backend, contents))
+    }
+
+    fn load_profiles(conn: &Connection) -> Result<BTreeMap<u64, Profile>> {
+        let mut profiles: BTreeMap<u64, Profile> = BTreeMap::new();
+        let mut stmt = conn.prepare("SELECT uid, key, value FROM settings")?;
+        let mut query = stmt.query([])?;
+        while let Some(row) = query.next()? {
+            let uid: u64 = row.get(0)?;
+            let key: String = row.get(1)?;
+            let value: String = row.get(2)?;
+            if let Err(e) = profiles.entry(uid).or_default().set(&key, &value) {
+                log::error!("ignoring setting {key} of {uid}: {e}");
+            }
+        }
+        Ok(profiles)
+    }
+
+    fn load_history(conn: &Connection) -> Result<Vec<Record>> {
+        let mut stmt = conn.prepare(
+            "SELECT uid, subscription, title, link, magnet, sent_at, status FROM history ORDER BY id",
+        )?;
+        let mut query = stmt.query([])?;
+        let mut history = Vec::new();
+        while let Some(row) = query.next()? {
+            let subscriptions: String = row.get(1)?;
+            let magnet: String = row.get(4)?;
+            let sent_at: String = row.get(5)?;
+            let status: String = row.get(6)?;
+            history.push(Record {
+                uid: row.get(0)?,
+                subscriptions: subscriptions.lines().map(String::from).collect(),
+                title: row.get(2)?,
+                link: row.get(3)?,
+                magnet: (!magnet.is_empty()).then_some(magnet),
+                sent_at: chrono::DateTime::parse_from_rfc3339(&sent_at)?.timestamp(),
+                status: Status::parse(&status),
+            });
+        }
+        Ok(history)
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("sqlite connection poisoned"))
+    }
+
+    /// Whether `user.bin` still has to be imported. Once the database was
+    /// filled it stays the source of truth, even if all subscriptions are
+    /// removed later.
+    pub fn needs_import(&self) -> bool {
+        !self.imported
+    }
+
+    /// Records that there is nothing (more) to import.
+    pub fn mark_imported(&mut self) -> Result<()> {
+        Self::write_imported(&*self.conn()?)?;
+        self.imported = true;
+        Ok(())
+    }
+
+    fn write_imported(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('imported', 1)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn insert(&mut self, e: &Entry, next_id: u64) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO users (uid) VALUES (?1)",
+            params![e.uid()],
+        )?;
+        tx.execute(
+            "INSERT INTO subscriptions (uid, entry) VALUES (?1, ?2)",
+            params![e.uid(), bincode::serialize(e)?],
+        )?;
+        let id = tx.last_insert_rowid();
+        Self::write_next_id(&tx, next_id)?;
+        Self::write_imported(&tx)?;
+        tx.commit()?;
+        drop(conn);
+        self.rows.push(id);
+        self.imported = true;
+        Ok(())
+    }
+
+    fn write_next_id(conn: &Connection, next_id: u64) -> Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('next_id', ?1)",
+            params![next_id],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces everything with the entries, profiles and history in one
+    /// transaction, so a failure halfway leaves the old data in place. Used
+    /// for the import of `user.bin` and restores.
+    fn replace_all(
+        &mut self,
+        entries: &[Entry],
+        profiles: &BTreeMap<u64, Profile>,
+        history: &[Record],
+        next_id: u64,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM subscriptions;
+            DELETE FROM settings;
+            DELETE FROM history;
+            DELETE FROM users;",
+        )?;
+        let mut ids = Vec::with_capacity(entries.len());
+        for e in entries {
+            tx.execute(
+                "INSERT OR IGNORE INTO users (uid) VALUES (?1)",
+                params![e.uid()],
+            )?;
+            tx.execute(
+                "INSERT INTO subscriptions (uid, entry) VALUES (?1, ?2)",
+                params![e.uid(), bincode::serialize(e)?],
+            )?;
+            ids.push(tx.last_insert_rowid());
+        }
+        for (uid, profile) in profiles {
+            Self::write_profile(&tx, *uid, profile)?;
+        }
+        for record in history {
+            Self::write_record(&tx, record)?;
+        }
+        Self::write_next_id(&tx, next_id)?;
+        Self::write_imported(&tx)?;
+        tx.commit()?;
+        drop(conn);
+        self.rows = ids;
+        self.imported = true;
+        Ok(())
+    }
+
+    fn set_profile(&self, uid: u64, profile: &Profile) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        Self::write_profile(&tx, uid, profile)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn write_profile(conn: &Connection, uid: u64, profile: &Profile) -> Result<()> {
+        conn.execute(
+            "INSERT OR IGNORE INTO users (uid) VALUES (?1)",
+            params![uid],
+        )?;
+        for key in Profile::KEYS {
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (uid, key, value) VALUES (?1, ?2, ?3)",
+                params![uid, key, profile.get(key)],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adds the record and drops the user's oldest ones above
+    /// [`history::LIMIT_PER_USER`].
+    fn record(&self, record: &Record) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        Self::write_record(&tx, record)?;
+        tx.execute(
+            "DELETE FROM history WHERE uid = ?1 AND id NOT IN
+                (SELECT id FROM history WHERE uid = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![record.uid, history::LIMIT_PER_USER],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn write_record(conn: &Connection, record: &Record) -> Result<()> {
+        conn.execute(
+            "INSERT INTO history (uid, subscription, title, link, magnet, sent_at, status)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.uid,
+                record.subscriptions.join("\n"),
+                record.title,
+                record.link,
+                record.magnet.as_deref().unwrap_or(""),
+                record.sent_at()?.to_rfc3339(),
+                record.status.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update(&self, pos: usize, e: &Entry) -> Result<()> {
+        self.conn()?.execute(
+            "UPDATE subscriptions SET entry = ?1 WHERE id = ?2",
+            params![bincode::serialize(e)?, self.rows[pos]],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&mut self, pos: usize) -> Result<()> {
+        self.conn()?.execute(
+            "DELETE FROM subscriptions WHERE id = ?1",
+            params![self.rows[pos]],
+        )?;
+        self.rows.remove(pos);
+        Ok(())
+    }
+
+    /// Removes all subscriptions of the user, `keep` tells which of the
+    /// store's entries stay.
+    fn remove_user(&mut self, uid: u64, keep: &[bool]) -> Result<()> {
+        self.conn()?
+            .execute("DELETE FROM subscriptions WHERE uid = ?1", params![uid])?;
+        let mut keep = keep.iter();
+        self.rows.retain(|_| *keep.next().unwrap_or(&true));
+        Ok(())
+    }
+}
+
+impl Storage for SqliteBackend {
+    fn save(&mut self, state: &State) -> Result<()> {
+        let history = state.history.to_vec();
+        self.replace_all(state.entries, state.profiles, &history, state.next_id)
+    }
+
+    fn added(&mut self, state: &State) -> Result<()> {
+        match state.entries.last() {
+            Some(e) => self.insert(e, state.next_id),
+            None => Ok(()),
+        }
+    }
+
+    fn updated(&mut self, state: &State, positions: &[usize]) -> Result<()> {
+        positions
+            .iter()
+            .try_for_each(|p| self.update(*p, &state.entries[*p]))
+    }
+
+    fn removed(&mut self, _state: &State, pos: usize) -> Result<()> {
+        self.remove(pos)
+    }
+
+    fn user_removed(&mut self, _state: &State, uid: u64, keep: &[bool]) -> Result<()> {
+        self.remove_user(uid, keep)
+    }
+
+    fn profile_changed(&mut self, state: &State, uid: u64) -> Result<()> {
+        match state.profiles.get(&uid) {
+            Some(profile) => self.set_profile(uid, profile),
+            None => Ok(()),
+        }
+    }
+
+    fn recorded(&mut self, _state: &State, record: &Record) -> Result<()> {
+        self.record(record)
+    }
+}
@@ -0,0 +1,401 @@
+use crate::format::{self, Contents};
+use crate::history::{self, History, Record};
+use crate::persist;
+use crate::profile::Profile;
+use crate::store::Entry;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How long [`WriteBehind`] waits after a change so a burst is written once.
+const COALESCE_DELAY: Duration = Duration::from_secs(2);
+/// How long [`WriteBehind`] waits before trying a failed write again.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Everything a [`crate::store::UserStore`] holds, after a change.
+pub struct State<'a> {
+    pub entries: &'a [Entry],
+    pub profiles: &'a BTreeMap<u64, Profile>,
+    pub history: &'a History,
+    /// The id the next subscription gets.
+    pub next_id: u64,
+}
+
+/// Where a [`crate::store::UserStore`] persists its changes. Every hook gets
+/// the state after the change, positions are indexes into its entries.
+///
+/// Backends that can't do better just implement [`Storage::save`], the
+/// hooks fall back to it.
+pub trait Storage: Send + Sync {
+    /// Stores the whole state, replacing whatever was stored before.
+    fn save(&mut self, state: &State) -> Result<()>;
+
+    /// A subscription was appended as the last entry.
+    fn added(&mut self, state: &State) -> Result<()> {
+        self.save(state)
+    }
+
+    /// The delivered episodes of these subscriptions changed.
+    fn updated(&mut self, state: &State, _positions: &[usize]) -> Result<()> {
+        self.save(state)
+    }
+
+    /// The subscription that was at `pos` is gone.
+    fn removed(&mut self, state: &State, _pos: usize) -> Result<()> {
+        self.save(state)
+    }
+
+    /// All subscriptions of the user are gone, `keep` tells which of the
+    /// entries before the change stayed.
+    fn user_removed(&mut self, state: &State, _uid: u64, _keep: &[bool]) -> Result<()> {
+        self.save(state)
+    }
+
+    fn profile_changed(&mut self, state: &State, _uid: u64) -> Result<()> {
+        self.save(state)
+    }
+
+    /// The record was appended to the history, older ones of the same user
+    /// may have been dropped.
+    fn recorded(&mut self, state: &State, _record: &Record) -> Result<()> {
+        self.save(state)
+    }
+
+    /// Makes sure every change handed over so far is stored.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// How writes are going, for backends that write in the background.
+    fn status(&self) -> Option<String> {
+        None
+    }
+}
+
+/// `user.bin` with the subscriptions and settings, rewritten on every
+/// change, and the history next to it in [`history::FILE_NAME`], which new
+/// records are appended to.
+pub struct FileStorage {
+    path: PathBuf,
+    history_path: PathBuf,
+    /// Records in the history file, including the ones that were dropped
+    /// since it was last rewritten.
+    lines: usize,
+}
+
+impl FileStorage {
+    /// Opens `user.bin` at `path` and the history next to it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<(Self, Contents)> {
+        let path = path.into();
+        let (contents, lines) = Self::read(&path)?;
+        let storage = FileStorage {
+            history_path: history_path(&path),
+            path,
+            lines,
+        };
+        Ok((storage, contents))
+    }
+
+    /// Reads `user.bin` at `path` and the history next to it, returns how
+    /// many records the history file has besides.
+    pub fn read(path: &Path) -> Result<(Contents, usize)> {
+        let mut contents = persist::read_recover(path, |data| Ok(format::decode(data)?.contents))?;
+        let (history, lines) = history::read_file(&history_path(path))?;
+        // files older than version 7 still have the history in user.bin,
+        // it is moved out when they are migrated
+        if contents.history.is_empty() {
+            contents.history = history;
+        }
+        Ok((contents, lines))
+    }
+
+    fn write_store(
+        &self,
+        entries: &[Entry],
+        profiles: &BTreeMap<u64, Profile>,
+        next_id: u64,
+    ) -> Result<()> {
+        persist::write_atomic(&self.path, &format::encode(entries, profiles, next_id)?)
+    }
+
+    fn write_history(&mut self, records: &[Record]) -> Result<()> {
+        history::write_file(&self.history_path, records)?;
+        self.lines = records.len();
+        Ok(())
+    }
+
+    fn append(&mut self, records: &[Record]) -> Result<()> {
+        history::append_file(&self.history_path, records)?;
+        self.lines += records.len();
+        Ok(())
+    }
+}
+
+/// Where the history of the `user.bin` at `path` is kept.
+pub fn history_path(path: &Path) -> PathBuf {
+    path.with_file_name(history::FILE_NAME)
+}
+
+/// Whether a history file with `lines` records is worth rewriting with
+/// the `kept` ones, which happens about once every `kept` appends.
+fn compaction_due(lines: usize, kept: usize) -> bool {
+    lines > 2 * kept + 100
+}
+
+impl Storage for FileStorage {
+    fn save(&mut self, state: &State) -> Result<()> {
+        self.write_history(&state.history.to_vec())?;
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn added(&mut self, state: &State) -> Result<()> {
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn updated(&mut self, state: &State, _positions: &[usize]) -> Result<()> {
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn removed(&mut self, state: &State, _pos: usize) -> Result<()> {
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn user_removed(&mut self, state: &State, _uid: u64, _keep: &[bool]) -> Result<()> {
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn profile_changed(&mut self, state: &State, _uid: u64) -> Result<()> {
+        self.write_store(state.entries, state.profiles, state.next_id)
+    }
+
+    fn recorded(&mut self, state: &State, record: &Record) -> Result<()> {
+        if compaction_due(self.lines + 1, state.history.len()) {
+            return self.write_history(&state.history.to_vec());
+        }
+        self.append(std::slice::from_ref(record))
+    }
+}
+
+/// Writes the changes to a [`FileStorage`] on a background task, so
+/// changes don't wait for the disk while the store is locked. The in-memory
+/// state stays authoritative, changes made while a write is running are
+/// coalesced into the next one and failed writes are retried. New records
+/// are queued on their own, so the history isn't copied on every change.
+pub struct WriteBehind {
+    shared: Arc<Shared>,
+    /// Records in the history file once the pending writes are done.
+    lines: usize,
+}
+
+struct Shared {
+    inner: Mutex<FileStorage>,
+    pending: Mutex<Pending>,
+    wake: Notify,
+    status: Mutex<FlushStatus>,
+}
+
+/// What isn't written yet, in the order it is written.
+#[derive(Default)]
+struct Pending {
+    /// The whole history, if the file has to be rewritten.
+    history: Option<Vec<Record>>,
+    /// Records to append after that.
+    records: Vec<Record>,
+    /// The newest subscriptions and settings, without history.
+    store: Option<Contents>,
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.history.is_none() && self.records.is_empty() && self.store.is_none()
+    }
+
+    /// Writes everything, what was written is taken out even if a later
+    /// part fails so a retry doesn't append records twice.
+    fn write(&mut self, inner: &mut FileStorage) -> Result<()> {
+        if let Some(history) = &self.history {
+            inner.write_history(history)?;
+            self.history = None;
+        }
+        if !self.records.is_empty() {
+            inner.append(&self.records)?;
+            self.records.clear();
+        }
+        if let Some(c) = &self.store {
+            inner.write_store(&c.entries, &c.profiles, c.next_id)?;
+            self.store = None;
+        }
+        Ok(())
+    }
+
+    /// Puts back what failed to be written in front of the changes that
+    /// came in since, which win where they overlap.
+    fn requeue(&mut self, failed: Pending) {
+        if self.store.is_none() {
+            self.store = failed.store;
+        }
+        if self.history.is_none() {
+            self.history = failed.history;
+            let newer = std::mem::replace(&mut self.records, failed.records);
+            self.records.extend(newer);
+        }
+    }
+}
+
+#[derive(Default)]
+struct FlushStatus {
+    last_flush: Option<DateTime<Utc>>,
+    last_error: Option<(DateTime<Utc>, String)>,
+}
+
+impl WriteBehind {
+    /// Starts the background task, must be called within a tokio runtime.
+    pub fn spawn(inner: FileStorage) -> Self {
+        let lines = inner.lines;
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(inner),
+            pending: Mutex::new(Pending::default()),
+            wake: Notify::new(),
+            status: Mutex::new(FlushStatus::default()),
+        });
+        tokio::spawn(flush_loop(Arc::clone(&shared)));
+        WriteBehind { shared, lines }
+    }
+
+    /// Queues the subscriptions and settings, the history stays as it is.
+    fn store_changed(&mut self, state: &State) -> Result<()> {
+        let contents = Contents {
+            entries: state.entries.to_vec(),
+            profiles: state.profiles.clone(),
+            history: Vec::new(),
+            next_id: state.next_id,
+        };
+        self.shared.pending.lock().unwrap().store = Some(contents);
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+}
+
+async fn flush_loop(shared: Arc<Shared>) {
+    loop {
+        shared.wake.notified().await;
+        tokio::time::sleep(COALESCE_DELAY).await;
+        let s = Arc::clone(&shared);
+        match tokio::task::spawn_blocking(move || s.write_pending()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                log::error!("writing the store failed, retrying: {e}");
+                tokio::time::sleep(RETRY_DELAY).await;
+                shared.wake.notify_one();
+            }
+            Err(e) => log::error!("store writer panicked: {e}"),
+        }
+    }
+}
+
+impl Shared {
+    fn write_pending(&self) -> Result<()> {
+        // taking the changes under the inner lock keeps older ones from
+        // being written after newer ones
+        let mut inner = self.inner.lock().unwrap();
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let result = pending.write(&mut inner);
+        let mut status = self.status.lock().unwrap();
+        match &result {
+            Ok(()) => {
+                status.last_flush = Some(Utc::now());
+                status.last_error = None;
+            }
+            Err(e) => {
+                status.last_error = Some((Utc::now(), e.to_string()));
+                self.pending.lock().unwrap().requeue(pending);
+            }
+        }
+        result
+    }
+}
+
+impl Storage for WriteBehind {
+    fn save(&mut self, state: &State) -> Result<()> {
+        let mut pending = self.shared.pending.lock().unwrap();
+        pending.history = Some(state.history.to_vec());
+        pending.records.clear();
+        drop(pending);
+        self.lines = state.history.len();
+        self.store_changed(state)
+    }
+
+    fn added(&mut self, state: &State) -> Result<()> {
+        self.store_changed(state)
+    }
+
+    fn updated(&mut self, state: &State, _positions: &[usize]) -> Result<()> {
+        self.store_changed(state)
+    }
+
+    fn removed(&mut self, state: &State, _pos: usize) -> Result<()> {
+        self.store_changed(state)
+    }
+
+    fn user_removed(&mut self, state: &State, _uid: u64, _keep: &[bool]) -> Result<()> {
+        self.store_changed(state)
+    }
+
+    fn profile_changed(&mut self, state: &State, _uid: u64) -> Result<()> {
+        self.store_changed(state)
+    }
+
+    fn recorded(&mut self, state: &State, record: &Record) -> Result<()> {
+        let mut pending = self.shared.pending.lock().unwrap();
+        self.lines += 1;
+        if compaction_due(self.lines, state.history.len()) {
+            pending.history = Some(state.history.to_vec());
+            pending.records.clear();
+            self.lines = state.history.len();
+        } else {
+            pending.records.push(record.clone());
+        }
+        drop(pending);
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.shared.write_pending()
+    }
+
+    fn status(&self) -> Option<String> {
+        let pending = !self.shared.pending.lock().unwrap().is_empty();
+        let status = self.shared.status.lock().unwrap();
+        let mut lines = vec![match status.last_flush {
+            Some(at) => format!("last written {}", at.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => "not written since start".into(),
+        }];
+        if pending {
+            lines.push("changes are waiting to be written".into());
+        }
+        if let Some((at, e)) = &status.last_error {
+            lines.push(format!(
+                "writing failed at {}: {e}",
+                at.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+/// Keeps nothing, for tests and throwaway stores.
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn save(&mut self, _state: &State) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,130 +1,1333 @@
+use crate::format::{self, Contents};
+use crate::history::{self, History, Record};
+use crate::index::MatchIndex;
+use crate::normalize::normalize;
+use crate::persist;
+use crate::profile::Profile;
+use crate::query::{Pattern, Query};
+use crate::release::ReleaseInfo;
+use crate::sqlite::SqliteBackend;
+use crate::storage::{self, FileStorage, MemoryStorage, State, Storage, WriteBehind};
+use crate::torrent::{format_size_exact, parse_size};
 use anyhow::{anyhow, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Per subscription switches, set with `--flag` in front of the query.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Default, Debug)]
+pub struct Flags {
+    /// Skips normalization and matches substrings byte for byte.
+    pub exact: bool,
+    /// Notifies again for a newer version (v2) or a REPACK of an episode
+    /// that was already delivered.
+    pub renotify: bool,
+    /// Release groups from most to least preferred. If set, releases of
+    /// the same episode are collected for a while and only the best one
+    /// is sent.
+    pub prefer: Vec<String>,
+    /// Payload size limits in bytes, checked once the `.torrent` is fetched.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Names of the feeds the subscription is limited to, all if empty.
+    pub feeds: Vec<String>,
+}
+
+impl Flags {
+    pub fn set(&mut self, flag: &str) -> Result<()> {
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (flag, None),
+        };
+        match (name, value) {
+            ("exact", None) => self.exact = true,
+            ("renotify", None) => self.renotify = true,
+            ("prefer", Some(groups)) => {
+                self.prefer = groups
+                    .split(',')
+                    .map(|g| g.trim().to_string())
+                    .filter(|g| !g.is_empty())
+                    .collect()
+            }
+            ("prefer", None) => return Err(anyhow!("use --prefer=Group1,Group2")),
+            ("min-size", Some(size)) => self.min_size = Some(parse_size(size)?),
+            ("max-size", Some(size)) => self.max_size = Some(parse_size(size)?),
+            ("min-size" | "max-size", None) => {
+                return Err(anyhow!("use --{name}=500MB, --{name}=2GB, ..."))
+            }
+            ("feed", Some(feeds)) => {
+                self.feeds = feeds
+                    .split(',')
+                    .map(|f| f.trim().to_string())
+                    .filter(|f| !f.is_empty())
+                    .collect()
+            }
+            ("feed", None) => return Err(anyhow!("use --feed=name1,name2")),
+            _ => return Err(anyhow!("unknown flag `--{flag}`")),
+        }
+        Ok(())
+    }
+
+    /// Whether a torrent of this size is wanted. Unknown sizes pass, better
+    /// a notification too many than a missed one.
+    pub fn allows_size(&self, size: Option<u64>) -> bool {
+        let Some(size) = size else {
+            return true;
+        };
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Position of the group in the preference list, unlisted groups rank last.
+    pub fn rank(&self, group: Option<&str>) -> usize {
+        group
+            .and_then(|g| {
+                let g = normalize(g);
+                self.prefer.iter().position(|p| normalize(p) == g)
+            })
+            .unwrap_or(self.prefer.len())
+    }
+
+    pub fn allows_feed(&self, feed: &str) -> bool {
+        self.feeds.is_empty() || self.feeds.iter().any(|f| f.eq_ignore_ascii_case(feed))
+    }
+}
+
+impl Display for Flags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut flags = Vec::new();
+        if self.exact {
+            flags.push("--exact".to_string());
+        }
+        if self.renotify {
+            flags.push("--renotify".to_string());
+        }
+        if !self.prefer.is_empty() {
+            flags.push(format!("--prefer={}", self.prefer.join(",")));
+        }
+        if let Some(min) = self.min_size {
+            flags.push(format!("--min-size={}", format_size_exact(min)));
+        }
+        if let Some(max) = self.max_size {
+            flags.push(format!("--max-size={}", format_size_exact(max)));
+        }
+        if !self.feeds.is_empty() {
+            flags.push(format!("--feed={}", self.feeds.join(",")));
+        }
+        write!(f, "{}", flags.join(" "))
+    }
+}
+
+/// The newest episode of a show that was sent for a subscription.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Delivered {
+    episode: u32,
+    version: u32,
+    repack: bool,
+}
+
+impl Delivered {
+    fn from_release(release: &ReleaseInfo) -> Option<Self> {
+        Some(Delivered {
+            episode: release.episode?,
+            version: release.version.unwrap_or(1),
+            repack: release.repack,
+        })
+    }
+
+    fn is_newer(&self, previous: &Delivered, renotify: bool) -> bool {
+        if self.episode != previous.episode || !renotify {
+            return self.episode > previous.episode;
+        }
+        self.version > previous.version || (self.repack && !previous.repack)
+    }
+}
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Entry {
     uid: u64,
-    patterns: Vec<String>,
+    /// Unique across the store and never reused, not even after removal,
+    /// shown to users in base 36. 0 until the store assigns one.
+    id: u64,
+    query: Query,
+    flags: Flags,
+    /// Keyed by [`show_key`], a subscription can match several shows.
+    delivered: BTreeMap<String, Delivered>,
 }
 
 impl Entry {
-    pub fn new(uid: u64, patterns: Vec<String>) -> Self {
-        Entry { uid, patterns }
+    pub fn new(uid: u64, query: Query, flags: Flags) -> Self {
+        Self::from_parts(uid, 0, query, flags, BTreeMap::new())
+    }
+
+    pub(crate) fn from_parts(
+        uid: u64,
+        id: u64,
+        query: Query,
+        flags: Flags,
+        delivered: BTreeMap<String, Delivered>,
+    ) -> Self {
+        Entry {
+            uid,
+            id,
+            query,
+            flags,
+            delivered,
+        }
+    }
+
+    pub fn uid(&self) -> u64 {
+        self.uid
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The id as users see and type it.
+    pub fn short_id(&self) -> String {
+        let mut n = self.id;
+        let mut digits = Vec::new();
+        loop {
+            digits.push(char::from_digit((n % 36) as u32, 36).unwrap());
+            n /= 36;
+            if n == 0 {
+                break;
+            }
+        }
+        digits.iter().rev().collect()
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    /// Whether the release is newer than what was delivered for its show.
+    /// Releases without show or episode can't be tracked and are always new.
+    fn is_new(&self, release: &ReleaseInfo) -> bool {
+        let (Some(show), Some(current)) = (show_key(release), Delivered::from_release(release))
+        else {
+            return true;
+        };
+        self.delivered
+            .get(&show)
+            .is_none_or(|previous| current.is_newer(previous, self.flags.renotify))
+    }
+
+    /// Records the release as delivered, returns false if an episode at
+    /// least as new was already sent and the user shouldn't hear about it.
+    fn claim(&mut self, release: &ReleaseInfo) -> bool {
+        if !self.is_new(release) {
+            return false;
+        }
+        if let (Some(show), Some(current)) = (show_key(release), Delivered::from_release(release)) {
+            self.delivered.insert(show, current);
+        }
+        true
     }
 }
 
+/// What episodes are tracked under: the normalized show name, with the
+/// season appended from season 2 on. Season 1 shares the key of releases
+/// without a season, as anime releases count episodes of the first season
+/// without one.
+fn show_key(release: &ReleaseInfo) -> Option<String> {
+    let show = normalize(release.show.as_ref()?);
+    Some(match release.season {
+        Some(season) if season > 1 => format!("{show} s{season}"),
+        _ => show,
+    })
+}
+
+/// A release of an episode that a subscription with a group preference
+/// matched. Competing releases of the same episode are collected under
+/// the same hold before one of them is sent.
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub struct Hold {
+    pub uid: u64,
+    pub id: u64,
+    pub subscription: String,
+    pub show: String,
+    pub episode: u32,
+}
+
+pub enum Claim {
+    /// Notify right away, the release was recorded as delivered.
+    Notify { uid: u64, subscription: String },
+    /// Wait for competing releases, see [`UserStore::claim_held`].
+    Hold(Hold, Flags),
+}
+
 pub struct UserStore {
     entries: Vec<Entry>,
-    path: PathBuf,
+    // compiled once on load/add so matching doesn't recompile per feed item
+    regexes: HashMap<String, Regex>,
+    normalized: HashMap<String, String>,
+    index: MatchIndex,
+    next_id: u64,
+    profiles: BTreeMap<u64, Profile>,
+    /// Delivered notifications.
+    history: History,
+    storage: Box<dyn Storage>,
+}
+
+/// The store as shared between the bot's tasks.
+pub type StoreHandle = Arc<RwLock<UserStore>>;
+
+/// Parses an id as shown by [`Entry::short_id`].
+pub fn parse_id(s: &str) -> Result<u64> {
+    u64::from_str_radix(s.trim(), 36).map_err(|_| anyhow!("`{s}` is not a subscription id"))
 }
 
 impl UserStore {
+    /// Opens the file at `path` and the history next to it, changes are
+    /// written to them in the background. Must be called within a tokio
+    /// runtime.
     pub fn from_path(path: impl Into<PathBuf>) -> Result<Self> {
-        let path = path.into();
-        let mut file = std::fs::File::open(&path)?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
-        let entries = bincode::deserialize(&data)?;
-        Ok(Self { entries, path })
-    }
-
-    pub fn save(&self) -> Result<()> {
-        let buf = self.path.clone();
-        let data = bincode::serialize(&self.entries)?;
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .append(false)
-            .truncate(true)
-            .open(buf)?;
-        file.write_all(&data)?;
+        let (file, contents) = FileStorage::open(path)?;
+        Self::new(contents, Box::new(WriteBehind::spawn(file)))
+    }
+
+    /// Rewrites the file at `path` in the current format if it was written
+    /// by an older version, the old file is kept as its backup. A history
+    /// in it is moved to its own file first.
+    pub fn migrate(path: &Path) -> Result<()> {
+        let decoded = persist::read_recover(path, format::decode)?;
+        if decoded.is_outdated() {
+            let Contents {
+                entries,
+                profiles,
+                history,
+                next_id,
+            } = &decoded.contents;
+            if !history.is_empty() {
+                history::write_file(&storage::history_path(path), history)?;
+            }
+            let data = format::encode(entries, profiles, *next_id)?;
+            persist::write_atomic(path, &data)?;
+            log::warn!(
+                "upgraded {} to format version {}",
+                path.display(),
+                format::VERSION
+            );
+        }
         Ok(())
     }
 
-    pub fn add(&mut self, e: Entry) -> Result<()> {
+    /// Opens the SQLite database at `path`. If it was never filled and a
+    /// `user.bin` exists at `import_from`, its entries are imported once
+    /// and the file and its backup are renamed.
+    pub fn from_sqlite(path: impl AsRef<Path>, import_from: impl AsRef<Path>) -> Result<Self> {
+        let (mut db, contents) = SqliteBackend::open(path)?;
+        let import_from = import_from.as_ref();
+        if !db.needs_import() {
+            return Self::new(contents, Box::new(db));
+        }
+        if !persist::exists(import_from) {
+            db.mark_imported()?;
+            return Self::new(contents, Box::new(db));
+        }
+        let imported = Self::read_file(import_from)?;
+        let mut store = Self::new(imported, Box::new(db))?;
+        store.persist(|storage, state| storage.save(state))?;
+        let history_path = storage::history_path(import_from);
+        for file in [
+            import_from.to_path_buf(),
+            persist::backup_path(import_from),
+            persist::backup_path(&history_path),
+            history_path,
+        ] {
+            if file.exists() {
+                let mut done = file.clone().into_os_string();
+                done.push(".imported");
+                std::fs::rename(&file, &done)?;
+            }
+        }
+        log::warn!(
+            "imported {} entries from {} into sqlite",
+            store.entries.len(),
+            import_from.display()
+        );
+        Ok(store)
+    }
+
+    pub fn new(contents: Contents, storage: Box<dyn Storage>) -> Result<Self> {
+        let mut store = Self {
+            entries: Vec::new(),
+            regexes: HashMap::new(),
+            normalized: HashMap::new(),
+            index: MatchIndex::default(),
+            next_id: contents.next_id.max(1),
+            profiles: contents.profiles,
+            history: History::new(contents.history),
+            storage,
+        };
+        for e in contents.entries {
+            store.insert(e)?;
+        }
+        Ok(store)
+    }
+
+    /// Swaps everything for `contents` and saves it, e.g. to restore a
+    /// snapshot. Ids handed out since then stay used.
+    pub fn replace(&mut self, contents: Contents) -> Result<()> {
+        let next_id = self.next_id;
+        let storage = std::mem::replace(&mut self.storage, Box::new(MemoryStorage));
+        *self = Self::new(contents, storage)?;
+        self.next_id = self.next_id.max(next_id);
+        self.persist(|storage, state| storage.save(state))
+    }
+
+    /// An empty store that keeps nothing on disk.
+    pub fn in_memory() -> Self {
+        Self::new(Contents::default(), Box::new(MemoryStorage)).unwrap()
+    }
+
+    fn read_file(path: &Path) -> Result<Contents> {
+        Ok(FileStorage::read(path)?.0)
+    }
+
+    fn compile_patterns(&mut self, e: &Entry) -> Result<()> {
+        for p in e.query.patterns() {
+            match p {
+                Pattern::Regex(re) => {
+                    if !self.regexes.contains_key(re) {
+                        self.regexes.insert(re.clone(), Regex::new(re)?);
+                    }
+                }
+                Pattern::Substring(s) if !e.flags.exact => {
+                    if !self.normalized.contains_key(s) {
+                        self.normalized.insert(s.clone(), normalize(s));
+                    }
+                }
+                Pattern::Substring(_) | Pattern::Field(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops compiled patterns no subscription uses anymore.
+    fn prune_patterns(&mut self) {
+        let mut regexes = HashSet::new();
+        let mut substrings = HashSet::new();
+        for e in &self.entries {
+            for p in e.query.patterns() {
+                match p {
+                    Pattern::Regex(re) => {
+                        regexes.insert(re.as_str());
+                    }
+                    Pattern::Substring(s) if !e.flags.exact => {
+                        substrings.insert(s.as_str());
+                    }
+                    Pattern::Substring(_) | Pattern::Field(_) => {}
+                }
+            }
+        }
+        self.regexes.retain(|re, _| regexes.contains(re.as_str()));
+        self.normalized
+            .retain(|s, _| substrings.contains(s.as_str()));
+    }
+
+    /// Hands the current state to the storage after a change.
+    fn persist(&mut self, f: impl FnOnce(&mut dyn Storage, &State) -> Result<()>) -> Result<()> {
+        let state = State {
+            entries: &self.entries,
+            profiles: &self.profiles,
+            history: &self.history,
+            next_id: self.next_id,
+        };
+        f(self.storage.as_mut(), &state)
+    }
+
+    /// Writes out changes the storage hasn't stored yet, blocks until done.
+    pub fn flush(&mut self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    /// How background writes are going, `None` if the storage writes
+    /// synchronously.
+    pub fn storage_status(&self) -> Option<String> {
+        self.storage.status()
+    }
+
+    fn insert(&mut self, mut e: Entry) -> Result<()> {
+        if e.id == 0 {
+            e.id = self.next_id;
+        }
+        self.next_id = self.next_id.max(e.id + 1);
+        self.compile_patterns(&e)?;
+        self.index
+            .insert(self.entries.len(), &e.query, e.flags.exact);
         self.entries.push(e);
-        self.save()
+        Ok(())
     }
 
-    pub fn get_elements_for_user(&self, user: u64) -> Vec<Vec<String>> {
+    /// Stores a new subscription and returns its id.
+    pub fn add(&mut self, e: Entry) -> Result<String> {
+        self.insert(e)?;
+        if let Err(err) = self.persist(|storage, state| storage.added(state)) {
+            let pos = self.entries.len() - 1;
+            self.entries.pop();
+            self.index.remove(pos);
+            return Err(err);
+        }
+        Ok(self.entries[self.entries.len() - 1].short_id())
+    }
+
+    pub fn get_elements_for_user(&self, user: u64) -> Vec<Entry> {
         self.entries
             .iter()
             .filter(|e| e.uid == user)
-            .map(|e| e.patterns.clone()) // cow?
+            .cloned() // cow?
             .collect()
     }
 
-    pub fn remove_by_index(&mut self, user: u64, i: usize) -> Result<()> {
-        let binding = self.get_elements_for_user(user);
-        let elem = binding.get(i).ok_or(anyhow!("Out of bounds"))?;
-        let searched_entry = Entry {
-            uid: user,
-            patterns: elem.clone(),
-        }; // cow!
+    pub fn remove_by_id(&mut self, user: u64, id: u64) -> Result<()> {
         let global_i = self
             .entries
             .iter()
-            .position(|e| *e == searched_entry)
-            .ok_or(anyhow!("global pat search failed"))?;
-        self.entries.remove(global_i);
-        self.save()?;
+            .position(|e| e.uid == user && e.id == id)
+            .ok_or(anyhow!("you have no subscription with that id"))?;
+        let removed = self.entries.remove(global_i);
+        self.index.remove(global_i);
+        self.prune_patterns();
+        if let Err(err) = self.persist(|storage, state| storage.removed(state, global_i)) {
+            // backends track the entries by position, they have to stay in line
+            self.entries.insert(global_i, removed);
+            self.reindex()?;
+            return Err(err);
+        }
         Ok(())
     }
 
     pub fn remove_user(&mut self, user: u64) -> Result<()> {
-        let new_vec = self
-            .entries
-            .clone()
-            .into_iter()
-            .filter(|e| e.uid != user)
-            .collect();
-        self.entries = new_vec;
-        self.save()?;
+        let keep: Vec<bool> = self.entries.iter().map(|e| e.uid != user).collect();
+        let before = self.entries.clone();
+        self.entries.retain(|e| e.uid != user);
+        self.prune_patterns();
+        self.reindex()?;
+        if let Err(err) = self.persist(|storage, state| storage.user_removed(state, user, &keep)) {
+            self.entries = before;
+            self.reindex()?;
+            return Err(err);
+        }
         Ok(())
     }
 
-    pub fn get_users_matching(&self, hay: &str) -> Vec<u64> {
-        self.entries
+    /// Rebuilds the index and compiles missing patterns after the entries
+    /// were changed wholesale.
+    fn reindex(&mut self) -> Result<()> {
+        let entries = std::mem::take(&mut self.entries);
+        self.index = MatchIndex::default();
+        for (i, e) in entries.iter().enumerate() {
+            self.compile_patterns(e)?;
+            self.index.insert(i, &e.query, e.flags.exact);
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// The user's settings, the defaults if they never changed any.
+    pub fn profile(&self, user: u64) -> Profile {
+        self.profiles.get(&user).cloned().unwrap_or_default()
+    }
+
+    pub fn set_profile(&mut self, user: u64, profile: Profile) -> Result<()> {
+        self.profiles.insert(user, profile);
+        self.persist(|storage, state| storage.profile_changed(state, user))
+    }
+
+    /// Remembers a notification, dropping the user's oldest ones above
+    /// [`history::LIMIT_PER_USER`].
+    pub fn record(&mut self, record: Record) -> Result<()> {
+        let uid = record.uid;
+        self.history.push(record);
+        self.persist(|storage, state| {
+            let record = state.history.user(uid).next_back().unwrap();
+            storage.recorded(state, record)
+        })
+    }
+
+    /// The user's last `n` notifications, newest first.
+    pub fn history(&self, user: u64, n: usize) -> Vec<&Record> {
+        self.history.user(user).rev().take(n).collect()
+    }
+
+    /// Notifications of all users whose title contains `title`, newest first.
+    pub fn lookup(&self, title: &str, n: usize) -> Vec<&Record> {
+        let title = normalize(title);
+        self.history
+            .all()
+            .into_iter()
+            .rev()
+            .filter(|r| normalize(&r.title).contains(&title))
+            .take(n)
+            .collect()
+    }
+
+    pub fn has_matches(&self, feed: &str, hay: &str, release: &ReleaseInfo) -> bool {
+        !self.matching_entries(feed, hay, release).is_empty()
+    }
+
+    /// Subscriptions matching the release. Subscriptions that already got
+    /// this or a newer episode of the show or don't want a torrent of this
+    /// size are skipped, the others remember the episode unless they wait
+    /// for competing releases. If the storage fails to save that, it is
+    /// logged and the users are notified anyway.
+    pub fn claim_matching(
+        &mut self,
+        feed: &str,
+        hay: &str,
+        release: &ReleaseInfo,
+        size: Option<u64>,
+    ) -> Vec<Claim> {
+        let mut claims = Vec::new();
+        let mut changed = Vec::new();
+        for i in self.matching_entries(feed, hay, release) {
+            let entry = &mut self.entries[i];
+            if !entry.flags.allows_size(size) {
+                continue;
+            }
+            match (show_key(release), release.episode) {
+                (Some(show), Some(episode)) if !entry.flags.prefer.is_empty() => {
+                    if entry.is_new(release) {
+                        let hold = Hold {
+                            uid: entry.uid,
+                            id: entry.id,
+                            subscription: entry.query.to_string(),
+                            show,
+                            episode,
+                        };
+                        claims.push(Claim::Hold(hold, entry.flags.clone()));
+                    }
+                }
+                (show, episode) => {
+                    if entry.claim(release) {
+                        claims.push(Claim::Notify {
+                            uid: entry.uid,
+                            subscription: entry.query.to_string(),
+                        });
+                        if show.is_some() && episode.is_some() {
+                            changed.push(i);
+                        }
+                    }
+                }
+            }
+        }
+        if !changed.is_empty() {
+            self.persist_claims(&changed);
+        }
+        claims
+    }
+
+    /// Records the release chosen for a hold as delivered. Returns false
+    /// if the subscription is gone or already got the episode.
+    pub fn claim_held(&mut self, hold: &Hold, release: &ReleaseInfo) -> bool {
+        let pos = self
+            .entries
             .iter()
-            .filter(|e| e.patterns.iter().all(|p| hay.contains(p)))
-            .map(|e| e.uid)
+            .position(|e| e.uid == hold.uid && e.id == hold.id);
+        let Some(pos) = pos else {
+            return false;
+        };
+        let claimed = self.entries[pos].claim(release);
+        if claimed {
+            self.persist_claims(&[pos]);
+        }
+        claimed
+    }
+
+    /// Saves delivered episodes. The claims already count in memory, so a
+    /// failure is only logged rather than losing the notification.
+    fn persist_claims(&mut self, positions: &[usize]) {
+        if let Err(e) = self.persist(|storage, state| storage.updated(state, positions)) {
+            log::error!("could not save delivered episodes: {e}");
+        }
+    }
+
+    /// Subscriptions matching an item of `feed`.
+    fn matching_entries(&self, feed: &str, hay: &str, release: &ReleaseInfo) -> Vec<usize> {
+        let normalized_hay = normalize(hay);
+        self.index
+            .candidates(hay, &normalized_hay)
+            .into_iter()
+            .filter(|i| {
+                let e = &self.entries[*i];
+                e.flags.allows_feed(feed) && self.query_matches(e, hay, &normalized_hay, release)
+            })
             .collect()
     }
+
+    fn query_matches(
+        &self,
+        e: &Entry,
+        hay: &str,
+        normalized_hay: &str,
+        release: &ReleaseInfo,
+    ) -> bool {
+        e.query
+            .matches(&|p| self.is_match(p, e.flags.exact, hay, normalized_hay, release))
+    }
+
+    /// Regexes always run against the raw title, substrings against the
+    /// normalized one unless the entry asked for exact matching.
+    fn is_match(
+        &self,
+        p: &Pattern,
+        exact: bool,
+        hay: &str,
+        normalized_hay: &str,
+        release: &ReleaseInfo,
+    ) -> bool {
+        match p {
+            Pattern::Substring(s) if exact => hay.contains(s.as_str()),
+            Pattern::Substring(s) => match self.normalized.get(s) {
+                Some(n) => normalized_hay.contains(n.as_str()),
+                None => normalized_hay.contains(&normalize(s)),
+            },
+            Pattern::Regex(re) => self.regexes.get(re).is_some_and(|r| r.is_match(hay)),
+            Pattern::Field(filter) => filter.matches(release),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::persist::TempDir;
+
+    fn term(s: &str) -> Query {
+        Query::Term(Pattern::Substring(s.to_string()))
+    }
+
+    fn store(entries: Vec<Entry>) -> UserStore {
+        let mut us = UserStore::in_memory();
+        for e in entries {
+            us.insert(e).unwrap();
+        }
+        us
+    }
+
+    fn exact() -> Flags {
+        Flags {
+            exact: true,
+            ..Flags::default()
+        }
+    }
+
+    fn matching(us: &UserStore, title: &str) -> Vec<u64> {
+        us.matching_entries("nyaa.si", title, &ReleaseInfo::parse(title))
+            .into_iter()
+            .map(|i| us.entries[i].uid)
+            .collect()
+    }
 
     #[test]
     fn test_user_match() {
-        let us = UserStore {
+        let us = store(vec![
+            Entry::new(1, Query::And(vec![term("")]), Flags::default()),
+            Entry::new(6, Query::And(vec![term("One ")]), Flags::default()),
+            Entry::new(9, Query::And(vec![term("Naru")]), Flags::default()),
+            Entry::new(8, Query::And(vec![term("O"), term("P")]), Flags::default()),
+            Entry::new(
+                11,
+                Query::And(vec![term("One"), Query::Not(Box::new(term("Piece")))]),
+                Flags::default(),
+            ),
+            Entry::new(
+                10,
+                Query::And(vec![term("One"), term("Love")]),
+                Flags::default(),
+            ),
+        ]);
+        assert_eq!(matching(&us, "One Piece"), vec![1, 6, 8])
+    }
+
+    #[test]
+    fn test_regex_match() {
+        let query = Query::parse(r#"re:"\[SubsPlease\] Frieren - \d+ \(1080p\)""#).unwrap();
+        let us = store(vec![Entry::new(3, query, Flags::default())]);
+        assert_eq!(
+            matching(&us, "[SubsPlease] Frieren - 12 (1080p).mkv"),
+            vec![3]
+        );
+        assert!(matching(&us, "[SubsPlease] Frieren - 12 (720p).mkv").is_empty());
+    }
+
+    #[test]
+    fn test_normalized_match() {
+        let us = store(vec![
+            Entry::new(1, term("one piece"), Flags::default()),
+            Entry::new(2, term("one piece"), exact()),
+        ]);
+        assert_eq!(matching(&us, "[Group] One.Piece - 1100"), vec![1]);
+        assert_eq!(matching(&us, "[Group] one piece - 1100"), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_field_match() {
+        let query = Query::parse(r#"group:SubsPlease res:1080 show:"Frieren""#).unwrap();
+        let us = store(vec![Entry::new(5, query, Flags::default())]);
+        assert_eq!(
+            matching(
+                &us,
+                "[SubsPlease] Sousou no Frieren - 12 (1080p) [ABCD1234].mkv"
+            ),
+            vec![5]
+        );
+        assert!(matching(&us, "[SubsPlease] Sousou no Frieren - 12 (720p).mkv").is_empty());
+        assert!(matching(&us, "[Erai-raws] Sousou no Frieren - 12 [1080p].mkv").is_empty());
+    }
+
+    #[test]
+    fn test_feed_scope() {
+        let mut anime = Flags::default();
+        anime.set("feed=anime-english,Anime-Raw").unwrap();
+        assert_eq!(anime.to_string(), "--feed=anime-english,Anime-Raw");
+        let us = store(vec![
+            Entry::new(1, term("Frieren"), anime),
+            Entry::new(2, term("Frieren"), Flags::default()),
+        ]);
+        let title = "[SubsPlease] Frieren - 12 (1080p)";
+        let release = ReleaseInfo::parse(title);
+        let uids = |feed| -> Vec<u64> {
+            us.matching_entries(feed, title, &release)
+                .into_iter()
+                .map(|i| us.entries[i].uid)
+                .collect()
+        };
+        assert_eq!(uids("anime-english"), vec![1, 2]);
+        assert_eq!(uids("anime-raw"), vec![1, 2]);
+        assert_eq!(uids("music"), vec![2]);
+    }
+
+    #[test]
+    fn test_flags_roundtrip() {
+        let mut flags = Flags::default();
+        for flag in ["min-size=1.4GB", "max-size=2GB", "prefer=ASW,SubsPlease"] {
+            flags.set(flag).unwrap();
+        }
+        let shown = flags.to_string();
+        assert!(shown.contains("--max-size=2GiB"));
+        let mut parsed = Flags::default();
+        for flag in shown.split_whitespace() {
+            parsed.set(flag.strip_prefix("--").unwrap()).unwrap();
+        }
+        assert_eq!(parsed, flags);
+    }
+
+    #[test]
+    fn test_episode_tracking() {
+        let renotify = Flags {
+            renotify: true,
+            ..Flags::default()
+        };
+        let mut us = store(vec![
+            Entry::new(1, term("SubsPlease"), Flags::default()),
+            Entry::new(2, term("SubsPlease"), renotify),
+        ]);
+        let mut claim = |title: &str| {
+            let release = ReleaseInfo::parse(title);
+            us.entries
+                .iter_mut()
+                .filter_map(|e| e.claim(&release).then_some(e.uid))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(claim("[SubsPlease] Frieren - 12 (1080p)"), vec![1, 2]);
+        assert!(claim("[SubsPlease] Frieren - 12 (1080p)").is_empty());
+        assert!(claim("[SubsPlease] Frieren - 11 (1080p)").is_empty());
+        assert_eq!(claim("[SubsPlease] Frieren - 12v2 (1080p)"), vec![2]);
+        assert_eq!(claim("[SubsPlease] One Piece - 3 (1080p)"), vec![1, 2]);
+        assert_eq!(claim("[SubsPlease] Frieren - 13 (1080p)"), vec![1, 2]);
+        assert_eq!(claim("[SubsPlease] Frieren Batch (1080p)"), vec![1, 2]);
+
+        // a new season starts counting again
+        assert_eq!(
+            claim("Dungeon.Meshi.S01E12.1080p.WEB.x264-SubsPlease"),
+            vec![1, 2]
+        );
+        assert_eq!(
+            claim("Dungeon.Meshi.S02E01.1080p.WEB.x264-SubsPlease"),
+            vec![1, 2]
+        );
+        assert!(claim("Dungeon.Meshi.S02E01.1080p.WEB.x264-SubsPlease").is_empty());
+        assert!(claim("Dungeon.Meshi.S01E11.1080p.WEB.x264-SubsPlease").is_empty());
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut flags = Flags::default();
+        flags.set("prefer=ASW, SubsPlease").unwrap();
+        assert_eq!(flags.rank(Some("asw")), 0);
+        assert_eq!(flags.rank(Some("subsplease")), 1);
+        assert_eq!(flags.rank(Some("Erai-raws")), 2);
+        assert_eq!(flags.rank(None), 2);
+    }
+
+    #[test]
+    fn test_holds() {
+        let mut prefer = Flags::default();
+        prefer.set("prefer=ASW").unwrap();
+        let mut us = store(vec![
+            Entry::new(1, term("Frieren"), prefer.clone()),
+            Entry::new(1, term("1080p"), Flags::default()),
+        ]);
+        let title = "[SubsPlease] Frieren - 12 (1080p)";
+        let release = ReleaseInfo::parse(title);
+        let claims = us.claim_matching("nyaa.si", title, &release, None);
+        let [Claim::Hold(hold, flags), Claim::Notify { uid: 1, .. }] = &claims[..] else {
+            panic!("expected a hold and a notification");
+        };
+        assert_eq!(
+            (hold.id, hold.show.as_str(), hold.episode),
+            (1, "frieren", 12)
+        );
+        assert_eq!(flags, &prefer);
+
+        // a hold doesn't mark the episode, so competing releases are held too
+        let asw = "[ASW] Frieren - 12 (1080p)";
+        let asw_release = ReleaseInfo::parse(asw);
+        let claims = us.claim_matching("nyaa.si", asw, &asw_release, None);
+        assert!(matches!(&claims[..], [Claim::Hold(h, _)] if h == hold));
+
+        assert!(us.claim_held(hold, &asw_release));
+        // only one release of the episode is delivered
+        assert!(!us.claim_held(hold, &release));
+        assert!(us
+            .claim_matching("nyaa.si", asw, &asw_release, None)
+            .is_empty());
+        us.remove_by_id(1, 1).unwrap();
+        let next = ReleaseInfo::parse("[ASW] Frieren - 13 (1080p)");
+        assert!(!us.claim_held(hold, &next));
+    }
+
+    #[test]
+    fn test_claim_despite_failed_save() {
+        struct Broken;
+        impl Storage for Broken {
+            fn save(&mut self, _state: &State) -> Result<()> {
+                Err(anyhow!("disk full"))
+            }
+        }
+        let contents = Contents {
+            entries: vec![Entry::new(1, term("Frieren"), Flags::default())],
+            ..Contents::default()
+        };
+        let mut us = UserStore::new(contents, Box::new(Broken)).unwrap();
+        let title = "[SubsPlease] Frieren - 12 (1080p)";
+        let release = ReleaseInfo::parse(title);
+        assert_eq!(us.claim_matching("nyaa.si", title, &release, None).len(), 1);
+        assert!(us
+            .claim_matching("nyaa.si", title, &release, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_failed_remove_keeps_entries() {
+        struct Broken;
+        impl Storage for Broken {
+            fn save(&mut self, _state: &State) -> Result<()> {
+                Err(anyhow!("disk full"))
+            }
+        }
+        let contents = Contents {
             entries: vec![
-                Entry {
-                    uid: 1,
-                    patterns: vec!["".to_string()],
-                },
-                Entry {
-                    uid: 6,
-                    patterns: vec!["One ".to_string()],
-                },
-                Entry {
-                    uid: 9,
-                    patterns: vec!["Naru".to_string()],
-                },
-                Entry {
-                    uid: 8,
-                    patterns: vec!["O".to_string(), "P".to_string()]
-                },
-                Entry {
-                    uid: 10,
-                    patterns: vec!["One".to_string(), "Love".to_string()]
-                },
+                Entry::new(1, term("Frieren"), Flags::default()),
+                Entry::new(2, Query::parse("re:Piece").unwrap(), Flags::default()),
+                Entry::new(2, term("Frieren"), Flags::default()),
             ],
-            path: Default::default(),
+            ..Contents::default()
         };
-        let res = us.get_users_matching("One Piece");
-        assert_eq!(res, vec![1, 6, 8])
+        let mut us = UserStore::new(contents, Box::new(Broken)).unwrap();
+        assert!(us.remove_by_id(1, 1).is_err());
+        assert!(us.remove_user(2).is_err());
+        let ids: Vec<u64> = us.entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(matching(&us, "[SubsPlease] Frieren - 12"), vec![1, 2]);
+        assert_eq!(matching(&us, "One Piece - 1100"), vec![2]);
+    }
+
+    #[test]
+    fn test_replace_keeps_ids() {
+        let mut us = UserStore::in_memory();
+        us.add(Entry::new(1, term("Frieren"), Flags::default()))
+            .unwrap();
+        let contents = Contents {
+            entries: us.entries.clone(),
+            next_id: us.next_id,
+            ..Contents::default()
+        };
+        assert_eq!(
+            us.add(Entry::new(1, term("Dungeon Meshi"), Flags::default()))
+                .unwrap(),
+            "2"
+        );
+        us.replace(contents).unwrap();
+        assert_eq!(
+            us.add(Entry::new(1, term("Dungeon Meshi"), Flags::default()))
+                .unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_index_after_remove() {
+        let mut us = store(vec![
+            Entry::new(1, term("Frieren"), Flags::default()),
+            Entry::new(
+                2,
+                Query::parse("Frieren OR re:Piece").unwrap(),
+                Flags::default(),
+            ),
+            Entry::new(3, term("One Piece"), exact()),
+        ]);
+        assert_eq!(matching(&us, "[SubsPlease] Frieren - 12"), vec![1, 2]);
+        us.entries.remove(0);
+        us.index.remove(0);
+        assert!(us.remove_by_id(1, 1).is_err());
+        assert_eq!(matching(&us, "[SubsPlease] Frieren - 12"), vec![2]);
+        assert_eq!(matching(&us, "One Piece - 1100"), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_caches_after_remove() {
+        let mut us = store(vec![
+            Entry::new(
+                1,
+                Query::parse("Frieren OR re:Piece").unwrap(),
+                Flags::default(),
+            ),
+            Entry::new(1, term("Dandadan"), Flags::default()),
+            Entry::new(2, term("Frieren"), Flags::default()),
+        ]);
+        us.remove_by_id(1, 2).unwrap();
+        assert!(!us.normalized.contains_key("Dandadan"));
+        us.remove_user(1).unwrap();
+        assert!(us.regexes.is_empty());
+        // still used by the other user's subscription
+        assert!(us.normalized.contains_key("Frieren"));
+        us.remove_user(2).unwrap();
+        assert!(us.normalized.is_empty());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_index`.
+    #[test]
+    #[ignore]
+    fn bench_index() {
+        let shows = [
+            "Frieren",
+            "One Piece",
+            "Dandadan",
+            "Kaiju No. 8",
+            "Spy x Family",
+        ];
+        let groups = ["SubsPlease", "Erai-raws", "ASW", "Judas", "EMBER"];
+        let entries: Vec<Entry> = (0..100_000u64)
+            .map(|i| {
+                let show = format!("{} {}", shows[i as usize % shows.len()], i / 25);
+                let group = groups[i as usize % groups.len()];
+                let query = match i % 4 {
+                    0 => format!("{show} AND 1080p"),
+                    1 => format!("group:{group} AND \"{show}\""),
+                    2 => format!("({group} OR Erai-raws) AND {show} AND -720p"),
+                    _ => show,
+                };
+                Entry::new(i, Query::parse(&query).unwrap(), Flags::default())
+            })
+            .collect();
+        let start = std::time::Instant::now();
+        let us = store(entries);
+        println!("indexing 100k subscriptions took {:?}", start.elapsed());
+
+        // a full page of a typical feed
+        let titles: Vec<(String, ReleaseInfo)> = (0..75)
+            .map(|i| {
+                let title = format!(
+                    "[{}] {} {} - {:02} (1080p) [ABCD{:04}].mkv",
+                    groups[i % groups.len()],
+                    shows[i % shows.len()],
+                    i * 53,
+                    i % 24,
+                    i
+                );
+                let release = ReleaseInfo::parse(&title);
+                (title, release)
+            })
+            .collect();
+        // build the automaton outside of the measurement
+        us.matching_entries("nyaa.si", "", &ReleaseInfo::default());
+
+        let start = std::time::Instant::now();
+        let indexed: Vec<Vec<usize>> = titles
+            .iter()
+            .map(|(t, r)| us.matching_entries("nyaa.si", t, r))
+            .collect();
+        let indexed_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let linear: Vec<Vec<usize>> = titles
+            .iter()
+            .map(|(t, r)| {
+                let n = normalize(t);
+                (0..us.entries.len())
+                    .filter(|i| us.query_matches(&us.entries[*i], t, &n, r))
+                    .collect()
+            })
+            .collect();
+        let linear_time = start.elapsed();
+
+        assert_eq!(indexed, linear);
+        println!("75 titles: indexed {indexed_time:?}, linear scan {linear_time:?}");
+    }
+
+    #[test]
+    fn test_in_memory_pipeline() {
+        let mut us = UserStore::in_memory();
+        let frieren = us
+            .add(Entry::new(1, term("Frieren"), Flags::default()))
+            .unwrap();
+        us.add(Entry::new(1, term("1080p"), Flags::default()))
+            .unwrap();
+        let title = "[SubsPlease] Sousou no Frieren - 12 (1080p)";
+        let release = ReleaseInfo::parse(title);
+        let claims = us.claim_matching("nyaa.si", title, &release, None);
+        let subscriptions: Vec<String> = claims
+            .into_iter()
+            .filter_map(|c| match c {
+                Claim::Notify {
+                    uid: 1,
+                    subscription,
+                } => Some(subscription),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(subscriptions, vec!["Frieren", "1080p"]);
+        assert!(us
+            .claim_matching("nyaa.si", title, &release, None)
+            .is_empty());
+
+        us.remove_by_id(1, parse_id(&frieren).unwrap()).unwrap();
+        assert!(us.remove_by_id(1, parse_id(&frieren).unwrap()).is_err());
+        assert_eq!(us.get_elements_for_user(1).len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_import_and_reopen() {
+        let dir = TempDir::new("sqlite");
+        let bin = dir.join("user.bin");
+        let db = dir.join("makima.db");
+        let entries = vec![
+            Entry::new(1, term("Frieren"), Flags::default()),
+            Entry::new(2, term("Dungeon Meshi"), Flags::default()),
+        ];
+        let mut profiles = BTreeMap::new();
+        let mut profile = Profile::default();
+        profile.set("timezone", "UTC+2").unwrap();
+        profiles.insert(1, profile.clone());
+        std::fs::write(&bin, format::encode(&entries, &profiles, 1).unwrap()).unwrap();
+
+        let mut us = UserStore::from_sqlite(&db, &bin).unwrap();
+        assert!(!bin.exists());
+        assert_eq!(matching(&us, "Frieren - 01"), vec![1]);
+        let id = us
+            .add(Entry::new(3, term("Frieren"), Flags::default()))
+            .unwrap();
+        assert_eq!(id, "3");
+        us.remove_user(1).unwrap();
+        profile.set("format", "plain").unwrap();
+        us.set_profile(2, profile.clone()).unwrap();
+        drop(us);
+
+        let us = UserStore::from_sqlite(&db, &bin).unwrap();
+        assert_eq!(us.profile(1).get("timezone"), "UTC+02:00");
+        assert_eq!(us.profile(2), profile);
+        assert_eq!(us.profile(3), Profile::default());
+        drop(us);
+
+        let mut us = UserStore::from_sqlite(&db, &bin).unwrap();
+        for i in 0..=history::LIMIT_PER_USER {
+            let record = Record {
+                uid: 2,
+                subscriptions: vec!["Dungeon Meshi".into(), "Meshi".into()],
+                title: format!("Dungeon Meshi - {i:02}"),
+                link: String::new(),
+                magnet: (i % 2 == 0).then(|| "magnet:?xt=urn:btih:abc".into()),
+                sent_at: 1_700_000_000 + i as i64,
+                status: history::Status::Sent,
+            };
+            us.record(record).unwrap();
+        }
+        drop(us);
+        let us = UserStore::from_sqlite(&db, &bin).unwrap();
+        let history = us.history(2, 500);
+        assert_eq!(history.len(), history::LIMIT_PER_USER);
+        assert_eq!(history[0].title, "Dungeon Meshi - 200");
+        assert_eq!(history[0].subscriptions.len(), 2);
+        assert_eq!(us.lookup("dungeon meshi - 00", 5).len(), 0);
+        assert_eq!(us.lookup("dungeon meshi - 01", 5).len(), 1);
+        assert_eq!(matching(&us, "Frieren - 01"), vec![3]);
+        assert_eq!(matching(&us, "Dungeon Meshi - 01"), vec![2]);
+    }
+
+    #[test]
+    fn test_sqlite_import_once() {
+        let dir = TempDir::new("sqlite-once");
+        let bin = dir.join("user.bin");
+        let db = dir.join("makima.db");
+        let entries = vec![Entry::new(1, term("Frieren"), Flags::default())];
+        let data = format::encode(&entries, &BTreeMap::new(), 1).unwrap();
+        std::fs::write(&bin, &data).unwrap();
+        std::fs::write(persist::backup_path(&bin), &data).unwrap();
+
+        let mut us = UserStore::from_sqlite(&db, &bin).unwrap();
+        assert!(!persist::exists(&bin));
+        us.remove_user(1).unwrap();
+        drop(us);
+        // a stale backup showing up again mustn't bring the removed ones back
+        std::fs::write(persist::backup_path(&bin), &data).unwrap();
+        let us = UserStore::from_sqlite(&db, &bin).unwrap();
+        assert!(us.entries.is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_failed_save_keeps_data() {
+        let dir = TempDir::new("sqlite-tx");
+        let db = dir.join("makima.db");
+        let mut us = UserStore::from_sqlite(&db, dir.join("user.bin")).unwrap();
+        us.add(Entry::new(1, term("Frieren"), Flags::default()))
+            .unwrap();
+        let broken = Record {
+            uid: 1,
+            subscriptions: Vec::new(),
+            title: String::new(),
+            link: String::new(),
+            magnet: None,
+            // can't be written as a date
+            sent_at: i64::MAX,
+            status: history::Status::Sent,
+        };
+        let contents = Contents {
+            history: vec![broken],
+            ..Contents::default()
+        };
+        assert!(us.replace(contents).is_err());
+        drop(us);
+        let us = UserStore::from_sqlite(&db, dir.join("user.bin")).unwrap();
+        assert_eq!(matching(&us, "Frieren - 01"), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_ids_not_reused() {
+        let dir = TempDir::new("ids");
+        let bin = dir.join("user.bin");
+        let db = dir.join("makima.db");
+        std::fs::write(&bin, format::encode(&[], &BTreeMap::new(), 1).unwrap()).unwrap();
+        let open = |sqlite: bool| match sqlite {
+            true => UserStore::from_sqlite(&db, dir.join("none.bin")).unwrap(),
+            false => UserStore::from_path(&bin).unwrap(),
+        };
+        for sqlite in [false, true] {
+            let mut us = open(sqlite);
+            us.add(Entry::new(1, term("Frieren"), Flags::default()))
+                .unwrap();
+            let newest = us
+                .add(Entry::new(1, term("Dandadan"), Flags::default()))
+                .unwrap();
+            us.remove_by_id(1, parse_id(&newest).unwrap()).unwrap();
+            us.flush().unwrap();
+            drop(us);
+            let mut us = open(sqlite);
+            let id = us
+                .add(Entry::new(1, term("Oshi no Ko"), Flags::default()))
+                .unwrap();
+            assert_eq!(id, "3");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_history() {
+        let dir = TempDir::new("history");
+        let bin = dir.join("user.bin");
+        let record = |i: usize| Record {
+            uid: 1,
+            subscriptions: vec!["Frieren".into()],
+            title: format!("Frieren - {i:02}"),
+            link: String::new(),
+            magnet: Some("magnet:?xt=urn:btih:abc".into()),
+            sent_at: 1_700_000_000 + i as i64,
+            status: history::Status::Sent,
+        };
+        // a version 6 file still has the history inside
+        let mut v6 = b"MKMA".to_vec();
+        v6.extend(6u16.to_le_bytes());
+        let old = (
+            Vec::<Entry>::new(),
+            BTreeMap::<u64, Profile>::new(),
+            vec![record(0)],
+            1u64,
+        );
+        v6.extend(bincode::serialize(&old).unwrap());
+        std::fs::write(&bin, v6).unwrap();
+        UserStore::migrate(&bin).unwrap();
+        let history_file = storage::history_path(&bin);
+        assert_eq!(
+            history::read_file(&history_file).unwrap().0,
+            vec![record(0)]
+        );
+
+        let mut us = UserStore::from_path(&bin).unwrap();
+        assert_eq!(us.history(1, 5).len(), 1);
+        us.add(Entry::new(1, term("Frieren"), Flags::default()))
+            .unwrap();
+        us.flush().unwrap();
+        let written = std::fs::read(&bin).unwrap();
+        for i in 1..=3 * history::LIMIT_PER_USER {
+            us.record(record(i)).unwrap();
+        }
+        us.flush().unwrap();
+        // records only go to the history file, which is compacted now and then
+        assert_eq!(std::fs::read(&bin).unwrap(), written);
+        let (kept, lines) = history::read_file(&history_file).unwrap();
+        assert_eq!(kept.len(), history::LIMIT_PER_USER);
+        assert!(lines <= 2 * history::LIMIT_PER_USER + 100);
+        drop(us);
+
+        let us = UserStore::from_path(&bin).unwrap();
+        let history = us.history(1, 500);
+        assert_eq!(history.len(), history::LIMIT_PER_USER);
+        assert_eq!(history[0], &record(3 * history::LIMIT_PER_USER));
+    }
+
+    #[tokio::test]
+    async fn test_write_behind() {
+        let dir = TempDir::new("behind");
+        let bin = dir.join("user.bin");
+        std::fs::write(&bin, format::encode(&[], &BTreeMap::new(), 1).unwrap()).unwrap();
+
+        let mut us = UserStore::from_path(&bin).unwrap();
+        for show in ["Frieren", "Dungeon Meshi", "Oshi no Ko"] {
+            us.add(Entry::new(1, term(show), Flags::default())).unwrap();
+        }
+        us.remove_by_id(1, 2).unwrap();
+        // nothing is written until the writer wakes up or the store is flushed
+        assert_eq!(UserStore::read_file(&bin).unwrap().entries.len(), 0);
+        us.flush().unwrap();
+        assert_eq!(UserStore::read_file(&bin).unwrap().entries.len(), 2);
+        assert!(us.storage_status().unwrap().starts_with("last written"));
+
+        // writes fail without the directory
+        std::fs::remove_dir_all(&*dir).unwrap();
+        us.add(Entry::new(1, term("Frieren"), Flags::default()))
+            .unwrap();
+        assert!(us.flush().is_err());
+        let status = us.storage_status().unwrap();
+        assert!(status.contains("waiting") && status.contains("failed"));
     }
 }
@@ -1,5 +1,5 @@
 extern crate url;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha1::Digest;
@@ -75,12 +75,41 @@ pub(crate) struct Torrent {
     created_by: Option<String>,
 }
 
+/// What we keep of a fetched `.torrent` once the magnet link is built.
+#[derive(Serialize, Deserialize)]
+pub struct ResolvedTorrent {
+    pub magnet: String,
+    pub size: u64,
+    pub file_count: usize,
+}
+
 impl Torrent {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
         let torrent: Torrent = serde_bencode::from_bytes(bytes)?;
         Ok(torrent)
     }
 
+    pub fn resolve(&self) -> Result<ResolvedTorrent> {
+        Ok(ResolvedTorrent {
+            magnet: self.create_magnet_link()?,
+            size: self.total_size(),
+            file_count: self.file_count(),
+        })
+    }
+
+    /// Payload size in bytes, summed over all files for multi file torrents.
+    pub fn total_size(&self) -> u64 {
+        match &self.info.files {
+            Some(files) => files.iter().map(|f| f.length.max(0) as u64).sum(),
+            None => self.info.length.unwrap_or(0).max(0) as u64,
+        }
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.info.files.as_ref().map_or(1, Vec::len)
+    }
+
+    /// The bare `magnet:?` link, encode it to put it into another URL.
     pub fn create_magnet_link(&self) -> Result<String> {
         let mut link = String::from("magnet:?");
         let mut params = vec![];
@@ -107,12 +136,69 @@ impl Torrent {
             &params
                 .into_iter()
                 .enumerate()
-                .map(|(i, (k, v))| format!("{}{}={}", if i == 0 { "" } else { "&" }, k, v))
+                .map(|(i, (k, v))| {
+                    // the hash is left readable like other magnet links show it
+                    let v = match k {
+                        "xt" => v,
+                        _ => form_urlencoded::byte_serialize(v.as_bytes()).collect(),
+                    };
+                    format!("{}{}={}", if i == 0 { "" } else { "&" }, k, v)
+                })
                 .collect::<String>(),
         );
-        let url_encoded = form_urlencoded::byte_serialize(link.as_bytes()).collect();
-        Ok(url_encoded)
+        Ok(link)
+    }
+}
+
+// longer suffixes first, `GIB` also ends with `B`
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("TIB", 1 << 40),
+    ("GIB", 1 << 30),
+    ("MIB", 1 << 20),
+    ("KIB", 1 << 10),
+    ("TB", 1 << 40),
+    ("GB", 1 << 30),
+    ("MB", 1 << 20),
+    ("KB", 1 << 10),
+    ("B", 1),
+];
+
+/// Parses sizes like `500MB`, `1.5GiB` or `734003200`. Units are binary,
+/// `1GB` is 1024 MB like most torrent sites display it.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let upper = s.trim().to_uppercase();
+    let (num, factor) = SIZE_UNITS
+        .iter()
+        .find_map(|(unit, factor)| upper.strip_suffix(unit).map(|n| (n.trim(), *factor)))
+        .unwrap_or((upper.as_str(), 1));
+    let num: f64 = num.parse().map_err(|_| anyhow!("`{s}` is not a size"))?;
+    // `nan` and `inf` parse as floats too
+    if num < 0.0 || !num.is_finite() {
+        return Err(anyhow!("`{s}` is not a size"));
     }
+    Ok((num * factor as f64) as u64)
+}
+
+pub fn format_size(size: u64) -> String {
+    for (unit, factor) in &SIZE_UNITS[..4] {
+        if size >= *factor {
+            let unit = unit.replace('I', "i");
+            return format!("{:.1} {unit}", size as f64 / *factor as f64);
+        }
+    }
+    format!("{size} B")
+}
+
+/// Like [`format_size`] but without rounding, so that [`parse_size`] reads
+/// back the same number: a unit is only used if it divides the size.
+pub fn format_size_exact(size: u64) -> String {
+    for (unit, factor) in &SIZE_UNITS[..4] {
+        if size >= *factor && size.is_multiple_of(*factor) {
+            let unit = unit.replace('I', "i");
+            return format!("{}{unit}", size / factor);
+        }
+    }
+    size.to_string()
 }
 
 impl Debug for Torrent {
@@ -153,3 +239,30 @@ impl Debug for Torrent {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        let data = b"d4:infod5:filesld6:lengthi100e4:pathl1:aeed6:lengthi50e4:pathl1:beee4:name1:t12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_bytes(data).unwrap();
+        assert_eq!(torrent.total_size(), 150);
+        assert_eq!(torrent.file_count(), 2);
+        assert_eq!(parse_size("500MB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("1.5 GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert!(parse_size("big").is_err());
+        for s in ["nan", "inf", "-inf", "infinity GB", "NaN MB"] {
+            assert!(parse_size(s).is_err());
+        }
+        assert_eq!(format_size(3 << 29), "1.5 GiB");
+        assert_eq!(format_size_exact(500 << 20), "500MiB");
+        assert_eq!(format_size_exact(3 << 29), "1536MiB");
+        assert_eq!(format_size_exact(1_500_000_000), "1500000000");
+        for size in [0, 1023, 3 << 29, 1_500_000_000] {
+            assert_eq!(parse_size(&format_size_exact(size)).unwrap(), size);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::feed::{self, Feed};
+use crate::profile::Profile;
+use crate::query::Query;
+use crate::store::{Entry, Flags, UserStore};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Version of the export file layout.
+const VERSION: u32 = 1;
+
+/// Bigger uploads are rejected before they are parsed.
+pub const MAX_FILE_SIZE: u32 = 1024 * 1024;
+
+/// How long an uploaded file waits for `import confirm`.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// A user's subscriptions and settings as written by `export`. Queries and
+/// flags are kept in the form users type them, so the file can be edited
+/// by hand and doesn't depend on how the store encodes entries.
+#[derive(Serialize, Deserialize)]
+struct Export {
+    version: u32,
+    subscriptions: Vec<Subscription>,
+    #[serde(default)]
+    settings: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Subscription {
+    query: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    flags: String,
+}
+
+/// A validated export file waiting for `import confirm`.
+pub struct Import {
+    subscriptions: Vec<(Query, Flags)>,
+    /// `None` if the file had no settings.
+    profile: Option<Profile>,
+    replace: bool,
+}
+
+pub fn export(entries: &[Entry], profile: &Profile) -> Result<Vec<u8>> {
+    let export = Export {
+        version: VERSION,
+        subscriptions: entries
+            .iter()
+            .map(|e| Subscription {
+                query: e.query().to_string(),
+                flags: e.flags().to_string(),
+            })
+            .collect(),
+        settings: Profile::KEYS
+            .iter()
+            .map(|k| (k.to_string(), profile.get(k)))
+            .collect(),
+    };
+    Ok(serde_json::to_vec_pretty(&export)?)
+}
+
+impl Import {
+    /// Parses and validates an export file against the configured feeds,
+    /// nothing is changed yet.
+    pub fn parse(data: &[u8], replace: bool, feeds: &[Feed]) -> Result<Self> {
+        let export: Export =
+            serde_json::from_slice(data).map_err(|e| anyhow!("not a makima export: {e}"))?;
+        if export.version > VERSION {
+            bail!("the file is from a newer version of makima");
+        }
+        let mut subscriptions = Vec::with_capacity(export.subscriptions.len());
+        for (i, s) in export.subscriptions.iter().enumerate() {
+            let parsed =
+                parse_subscription(s, feeds).map_err(|e| anyhow!("subscription {}: {e}", i + 1))?;
+            subscriptions.push(parsed);
+        }
+        let mut profile = None;
+        if !export.settings.is_empty() {
+            let p = profile.insert(Profile::default());
+            for (key, value) in &export.settings {
+                p.set(key, value)?;
+            }
+        }
+        Ok(Import {
+            subscriptions,
+            profile,
+            replace,
+        })
+    }
+
+    /// The subscriptions that aren't in `existing` yet.
+    fn new_subscriptions<'a>(&'a self, existing: &[Entry]) -> Vec<&'a (Query, Flags)> {
+        self.subscriptions
+            .iter()
+            .filter(|(q, f)| {
+                self.replace || !existing.iter().any(|e| e.query() == q && e.flags() == f)
+            })
+            .collect()
+    }
+
+    /// What [`Import::apply`] would do to the user's subscriptions and settings.
+    pub fn preview(&self, existing: &[Entry], profile: &Profile) -> String {
+        let new = self.new_subscriptions(existing);
+        let mut lines = Vec::new();
+        if self.replace {
+            lines.push(format!(
+                "replaces your {} subscriptions with these {}:",
+                existing.len(),
+                new.len()
+            ));
+        } else {
+            lines.push(format!(
+                "adds {} subscriptions, {} you already have are skipped:",
+                new.len(),
+                self.subscriptions.len() - new.len()
+            ));
+        }
+        for (q, f) in new.iter().take(15) {
+            match f.to_string().as_str() {
+                "" => lines.push(format!("+ {q}")),
+                flags => lines.push(format!("+ {flags} {q}")),
+            }
+        }
+        if new.len() > 15 {
+            lines.push(format!("... and {} more", new.len() - 15));
+        }
+        if let Some(imported) = &self.profile {
+            for key in Profile::KEYS {
+                let (old, new) = (profile.get(key), imported.get(key));
+                if old != new {
+                    lines.push(format!("{key}: {old} -> {new}"));
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Applies the import, returns how many subscriptions were added.
+    pub fn apply(self, store: &mut UserStore, uid: u64) -> Result<usize> {
+        let existing = store.get_elements_for_user(uid);
+        let new: Vec<(Query, Flags)> = self
+            .new_subscriptions(&existing)
+            .into_iter()
+            .cloned()
+            .collect();
+        if self.replace {
+            store.remove_user(uid)?;
+        }
+        for (query, flags) in &new {
+            store.add(Entry::new(uid, query.clone(), flags.clone()))?;
+        }
+        if let Some(profile) = self.profile {
+            store.set_profile(uid, profile)?;
+        }
+        Ok(new.len())
+    }
+}
+
+fn parse_subscription(s: &Subscription, feeds: &[Feed]) -> Result<(Query, Flags)> {
+    let mut flags = Flags::default();
+    for flag in s.flags.split_whitespace() {
+        let name = flag
+            .strip_prefix("--")
+            .ok_or(anyhow!("flags start with --, got `{flag}`"))?;
+        flags.set(name)?;
+    }
+    feed::check_known(&flags.feeds, feeds)?;
+    Ok((Query::parse(&s.query)?, flags))
+}
+
+/// Validated imports waiting for `import confirm`, one per user. They
+/// expire after [`PENDING_TIMEOUT`] so forgotten uploads don't pile up.
+#[derive(Default)]
+pub struct PendingImports {
+    imports: Mutex<HashMap<u64, (Instant, Import)>>,
+}
+
+impl PendingImports {
+    /// Keeps the import until the user confirms or cancels it, replacing an
+    /// earlier one.
+    pub fn stage(&self, uid: u64, import: Import) {
+        self.stage_at(uid, import, Instant::now());
+    }
+
+    fn stage_at(&self, uid: u64, import: Import, now: Instant) {
+        let mut imports = self.imports.lock().unwrap();
+        imports.retain(|_, (staged, _)| now.duration_since(*staged) < PENDING_TIMEOUT);
+        imports.insert(uid, (now, import));
+    }
+
+    /// The user's import, `None` if there is none or it expired.
+    pub fn take(&self, uid: u64) -> Option<Import> {
+        self.take_at(uid, Instant::now())
+    }
+
+    fn take_at(&self, uid: u64, now: Instant) -> Option<Import> {
+        let (staged, import) = self.imports.lock().unwrap().remove(&uid)?;
+        (now.duration_since(staged) < PENDING_TIMEOUT).then_some(import)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feeds() -> Vec<Feed> {
+        let interval = Duration::from_secs(60);
+        vec![feed::single_feed("https://nyaa.si/?page=rss", interval, interval).unwrap()]
+    }
+
+    #[test]
+    fn test_export_roundtrip() {
+        let mut flags = Flags::default();
+        flags.set("prefer=SubsPlease,Erai-raws").unwrap();
+        flags.set("exact").unwrap();
+        // not a whole number of any unit
+        flags.set("max-size=1.37GB").unwrap();
+        flags.set("feed=nyaa.si").unwrap();
+        let query = Query::parse("(Frieren OR re:\"Sousou\") AND 1080p -batch").unwrap();
+        let entries = vec![Entry::new(1, query.clone(), flags.clone())];
+        let mut profile = Profile::default();
+        profile.set("quiet", "22-7").unwrap();
+
+        let data = export(&entries, &profile).unwrap();
+        let import = Import::parse(&data, false, &feeds()).unwrap();
+        assert_eq!(import.subscriptions, vec![(query, flags)]);
+        assert_eq!(import.profile, Some(profile.clone()));
+        // everything is already there
+        assert!(import.new_subscriptions(&entries).is_empty());
+        assert!(import.preview(&entries, &profile).starts_with("adds 0"));
+
+        let mut store = UserStore::in_memory();
+        store.add(entries[0].clone()).unwrap();
+        assert_eq!(import.apply(&mut store, 1).unwrap(), 0);
+        assert_eq!(store.get_elements_for_user(1).len(), 1);
+    }
+
+    #[test]
+    fn test_pending_expiry() {
+        let pending = PendingImports::default();
+        let import =
+            || Import::parse(br#"{"version": 1, "subscriptions": []}"#, false, &[]).unwrap();
+        let start = Instant::now();
+        pending.stage_at(1, import(), start);
+        pending.stage_at(2, import(), start);
+        assert!(pending.take_at(1, start + PENDING_TIMEOUT / 2).is_some());
+        assert!(pending.take_at(1, start).is_none());
+        assert!(pending.take_at(2, start + PENDING_TIMEOUT).is_none());
+
+        pending.stage_at(3, import(), start);
+        pending.stage_at(4, import(), start + PENDING_TIMEOUT);
+        assert_eq!(pending.imports.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_import_validation() {
+        let bad_query =
+            r#"{"version": 1, "subscriptions": [{"query": "a"}, {"query": "re:\"(\""}]}"#;
+        let e = Import::parse(bad_query.as_bytes(), false, &feeds())
+            .err()
+            .unwrap();
+        assert!(e.to_string().starts_with("subscription 2"));
+        let bad_flag = r#"{"version": 1, "subscriptions": [{"query": "a", "flags": "--loud"}]}"#;
+        assert!(Import::parse(bad_flag.as_bytes(), false, &feeds()).is_err());
+        let bad_feed = r#"{"version": 1, "subscriptions": [{"query": "a", "flags": "--feed=nyaa.si,tokyotosho"}]}"#;
+        let e = Import::parse(bad_feed.as_bytes(), false, &feeds())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("there is no feed `tokyotosho`"));
+        let newer = r#"{"version": 2, "subscriptions": []}"#;
+        assert!(Import::parse(newer.as_bytes(), false, &feeds()).is_err());
+        assert!(Import::parse(b"user.bin", false, &feeds()).is_err());
+    }
+}